@@ -0,0 +1,59 @@
+//! Measures how many instructions per second the emulator executes by computing the 12th
+//! fibonacci number over and over, once with a concrete bus and once with a boxed one. The
+//! fastest of several rounds is reported, which is the least disturbed by other processes.
+//!
+//! ```
+//! cargo run --release --example benchmark
+//! ```
+
+use std::time::{Duration, Instant};
+
+use micro_6502::assembler::assemble;
+use micro_6502::emulator::{DynEmulator, Emulator};
+use micro_6502::mem::Memory;
+use micro_6502::readwritable::ReadWritable;
+
+const RUNS: u32 = 500;
+const ROUNDS: u32 = 5;
+
+fn run<B: ReadWritable>(emulator: &mut Emulator<B>) -> (u64, Duration) {
+    let mut instructions = 0;
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        emulator.reset();
+        // Resetting moves the stack pointer down like the hardware does, so start over instead
+        let regs = emulator.get_regs_mut();
+        regs.sp = 0xff;
+        regs.x = 12;
+        while !emulator.step().halted {
+            instructions += 1;
+        }
+        instructions += 1;
+    }
+    (instructions, start.elapsed())
+}
+
+fn best_of<B: ReadWritable>(emulator: &mut Emulator<B>) -> (u64, Duration) {
+    (0..ROUNDS)
+        .map(|_| run(emulator))
+        .min_by_key(|&(_, elapsed)| elapsed)
+        .unwrap()
+}
+
+fn report(name: &str, (instructions, elapsed): (u64, Duration)) {
+    println!(
+        "{name:<16} {instructions} instructions in {:.2}s, {:.2}M instructions/s",
+        elapsed.as_secs_f64(),
+        instructions as f64 / elapsed.as_secs_f64() / 1_000_000.0
+    );
+}
+
+fn main() {
+    let image = assemble(include_str!("fibonacci.asm")).expect("Cannot assemble the program");
+
+    let mut emulator = Emulator::new(Memory::new_from_bytes(image));
+    report("Emulator<Memory>", best_of(&mut emulator));
+
+    let mut emulator: DynEmulator = Emulator::new(Box::new(Memory::new_from_bytes(image)));
+    report("DynEmulator", best_of(&mut emulator));
+}
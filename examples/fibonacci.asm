@@ -17,6 +17,7 @@ tya
 dex
 jsr fib
 sty $f7
+clc
 adc $f7
 tay
 pla
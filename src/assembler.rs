@@ -0,0 +1,679 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use crate::decoder::get_registry;
+use crate::instruction::{AddressingMode, InstructionName, InstructionRegistry};
+use crate::mem::MEM_SIZE;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssemblerErrorKind {
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    InvalidOperand(String),
+    MissingOperand(InstructionName),
+    InvalidNumber(String),
+    InvalidLabel(String),
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+    UnsupportedAddressingMode(InstructionName, AddressingMode),
+    BranchOutOfRange(i64),
+    ValueOutOfRange(i64),
+    AddressOverflow,
+}
+
+impl Display for AssemblerErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssemblerErrorKind::UnknownMnemonic(mnemonic) => {
+                write!(f, "unknown mnemonic '{mnemonic}'")
+            }
+            AssemblerErrorKind::UnknownDirective(directive) => {
+                write!(f, "unknown directive '{directive}'")
+            }
+            AssemblerErrorKind::InvalidOperand(operand) => write!(f, "invalid operand '{operand}'"),
+            AssemblerErrorKind::MissingOperand(name) => write!(f, "{name} needs an operand"),
+            AssemblerErrorKind::InvalidNumber(number) => write!(f, "invalid number '{number}'"),
+            AssemblerErrorKind::InvalidLabel(label) => write!(f, "invalid label '{label}'"),
+            AssemblerErrorKind::DuplicateLabel(label) => {
+                write!(f, "label '{label}' is already defined")
+            }
+            AssemblerErrorKind::UndefinedLabel(label) => write!(f, "undefined label '{label}'"),
+            AssemblerErrorKind::UnsupportedAddressingMode(name, mode) => {
+                write!(f, "{name} does not support the {mode} addressing mode")
+            }
+            AssemblerErrorKind::BranchOutOfRange(offset) => {
+                write!(f, "branch offset {offset} does not fit in a signed byte")
+            }
+            AssemblerErrorKind::ValueOutOfRange(value) => {
+                write!(f, "value {value} does not fit in the operand")
+            }
+            AssemblerErrorKind::AddressOverflow => write!(f, "program does not fit in memory"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblerError {
+    pub line: usize,
+    pub kind: AssemblerErrorKind,
+}
+
+impl AssemblerError {
+    fn new(line: usize, kind: AssemblerErrorKind) -> Self {
+        Self { line, kind }
+    }
+}
+
+impl Display for AssemblerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+pub struct Program {
+    pub image: [u8; MEM_SIZE],
+    pub labels: HashMap<String, u16>,
+    /// Where each instruction and data directive was placed, in source order
+    pub listing: Vec<ListingLine>,
+}
+
+/// A source line that emitted bytes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ListingLine {
+    /// The line number in the source, starting at 1
+    pub line: usize,
+    pub address: u16,
+    pub size: u16,
+    /// False for `.byte` and `.word`
+    pub is_instruction: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteSelector {
+    Whole,
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Term {
+    Number(i64),
+    Label(String),
+    Current,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Expr {
+    selector: ByteSelector,
+    terms: Vec<(bool, Term)>,
+}
+
+impl Expr {
+    fn parse(text: &str) -> Result<Self, AssemblerErrorKind> {
+        let text = text.trim();
+        let (selector, text) = if let Some(rest) = text.strip_prefix('<') {
+            (ByteSelector::Low, rest.trim_start())
+        } else if let Some(rest) = text.strip_prefix('>') {
+            (ByteSelector::High, rest.trim_start())
+        } else {
+            (ByteSelector::Whole, text)
+        };
+
+        let mut terms = Vec::new();
+        let mut negative = false;
+        let mut current = String::new();
+        for c in text.chars() {
+            match c {
+                '+' | '-' if current.trim().is_empty() => {
+                    if c == '-' {
+                        negative = !negative;
+                    }
+                }
+                '+' | '-' => {
+                    terms.push((negative, Self::parse_term(current.trim())?));
+                    current.clear();
+                    negative = c == '-';
+                }
+                _ => current.push(c),
+            }
+        }
+        if current.trim().is_empty() {
+            return Err(AssemblerErrorKind::InvalidOperand(text.to_string()));
+        }
+        terms.push((negative, Self::parse_term(current.trim())?));
+
+        Ok(Self { selector, terms })
+    }
+
+    fn parse_term(text: &str) -> Result<Term, AssemblerErrorKind> {
+        if text == "*" {
+            return Ok(Term::Current);
+        }
+        if is_identifier(text) {
+            return Ok(Term::Label(text.to_string()));
+        }
+        parse_number(text)
+            .map(Term::Number)
+            .ok_or_else(|| AssemblerErrorKind::InvalidNumber(text.to_string()))
+    }
+
+    fn evaluate(
+        &self,
+        labels: &HashMap<String, u16>,
+        pc: u16,
+    ) -> Result<Option<i64>, AssemblerErrorKind> {
+        let mut value = 0i64;
+        for (negative, term) in &self.terms {
+            let term_value = match term {
+                Term::Number(number) => *number,
+                Term::Current => pc as i64,
+                Term::Label(label) => match labels.get(label) {
+                    Some(address) => *address as i64,
+                    None => return Ok(None),
+                },
+            };
+            if *negative {
+                value -= term_value;
+            } else {
+                value += term_value;
+            }
+        }
+
+        Ok(Some(match self.selector {
+            ByteSelector::Whole => value,
+            ByteSelector::Low => value & 0xff,
+            ByteSelector::High => (value >> 8) & 0xff,
+        }))
+    }
+
+    fn resolve(&self, labels: &HashMap<String, u16>, pc: u16) -> Result<i64, AssemblerErrorKind> {
+        match self.evaluate(labels, pc)? {
+            Some(value) => Ok(value),
+            None => {
+                let undefined = self
+                    .terms
+                    .iter()
+                    .find_map(|(_, term)| match term {
+                        Term::Label(label) if !labels.contains_key(label) => Some(label.clone()),
+                        _ => None,
+                    })
+                    .unwrap_or_default();
+                Err(AssemblerErrorKind::UndefinedLabel(undefined))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Direct(Expr),
+    DirectX(Expr),
+    DirectY(Expr),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+impl Operand {
+    fn parse(text: &str) -> Result<Self, AssemblerErrorKind> {
+        let text = text.trim();
+        let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+        let lower = compact.to_ascii_lowercase();
+        let invalid = || AssemblerErrorKind::InvalidOperand(text.to_string());
+
+        if text.is_empty() {
+            return Ok(Operand::None);
+        }
+        if lower == "a" {
+            return Ok(Operand::Accumulator);
+        }
+        if let Some(rest) = text.strip_prefix('#') {
+            return Ok(Operand::Immediate(Expr::parse(rest)?));
+        }
+        if text.starts_with('(') {
+            let inner = |suffix: &str| {
+                let end = lower.len() - suffix.len();
+                Expr::parse(&compact[1..end]).map_err(|_| invalid())
+            };
+            return if lower.ends_with(",x)") {
+                Ok(Operand::IndirectX(inner(",x)")?))
+            } else if lower.ends_with("),y") {
+                Ok(Operand::IndirectY(inner("),y")?))
+            } else if lower.ends_with(')') {
+                Ok(Operand::Indirect(inner(")")?))
+            } else {
+                Err(invalid())
+            };
+        }
+        if let Some((expr, index)) = text.rsplit_once(',') {
+            return match index.trim().to_lowercase().as_str() {
+                "x" => Ok(Operand::DirectX(Expr::parse(expr)?)),
+                "y" => Ok(Operand::DirectY(Expr::parse(expr)?)),
+                _ => Err(invalid()),
+            };
+        }
+
+        Ok(Operand::Direct(Expr::parse(text)?))
+    }
+
+    fn expr(&self) -> Option<&Expr> {
+        match self {
+            Operand::None | Operand::Accumulator => None,
+            Operand::Immediate(expr)
+            | Operand::Direct(expr)
+            | Operand::DirectX(expr)
+            | Operand::DirectY(expr)
+            | Operand::Indirect(expr)
+            | Operand::IndirectX(expr)
+            | Operand::IndirectY(expr) => Some(expr),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+    Byte(Vec<Expr>),
+    Word(Vec<Expr>),
+    Instruction {
+        name: InstructionName,
+        mode: AddressingMode,
+        operand: Option<Expr>,
+    },
+}
+
+struct Located {
+    line: usize,
+    address: u16,
+    statement: Statement,
+}
+
+pub struct Assembler {
+    registry: &'static InstructionRegistry,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self {
+            registry: get_registry(),
+        }
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Program, AssemblerError> {
+        let (statements, labels) = self.first_pass(source)?;
+
+        let mut image = [0u8; MEM_SIZE];
+        let mut listing = Vec::with_capacity(statements.len());
+        for located in statements {
+            let bytes = self
+                .encode(&located, &labels)
+                .map_err(|kind| AssemblerError::new(located.line, kind))?;
+            let start = located.address as usize;
+            image[start..start + bytes.len()].copy_from_slice(&bytes);
+            listing.push(ListingLine {
+                line: located.line,
+                address: located.address,
+                size: bytes.len() as u16,
+                is_instruction: matches!(located.statement, Statement::Instruction { .. }),
+            });
+        }
+
+        Ok(Program {
+            image,
+            labels,
+            listing,
+        })
+    }
+
+    fn first_pass(
+        &self,
+        source: &str,
+    ) -> Result<(Vec<Located>, HashMap<String, u16>), AssemblerError> {
+        let mut labels: HashMap<String, u16> = HashMap::new();
+        let mut statements = Vec::new();
+        let mut pc: usize = 0;
+
+        for (index, raw_line) in source.lines().enumerate() {
+            let line = index + 1;
+            let error = |kind| AssemblerError::new(line, kind);
+            let mut text = raw_line.split(';').next().unwrap_or("").trim();
+
+            if let Some((label, rest)) = text.split_once(':') {
+                let label = label.trim();
+                if !is_identifier(label) || label.eq_ignore_ascii_case("a") {
+                    return Err(error(AssemblerErrorKind::InvalidLabel(label.to_string())));
+                }
+                if pc >= MEM_SIZE {
+                    return Err(error(AssemblerErrorKind::AddressOverflow));
+                }
+                if labels.insert(label.to_string(), pc as u16).is_some() {
+                    return Err(error(AssemblerErrorKind::DuplicateLabel(label.to_string())));
+                }
+                text = rest.trim();
+            }
+            if text.is_empty() {
+                continue;
+            }
+
+            let (keyword, rest) = match text.split_once(char::is_whitespace) {
+                Some((keyword, rest)) => (keyword, rest.trim()),
+                None => (text, ""),
+            };
+            let statement = if let Some(directive) = keyword.strip_prefix('.') {
+                match directive.to_lowercase().as_str() {
+                    "org" => {
+                        let value = Expr::parse(rest)
+                            .and_then(|expr| expr.resolve(&labels, pc as u16))
+                            .map_err(error)?;
+                        if !(0..MEM_SIZE as i64).contains(&value) {
+                            return Err(error(AssemblerErrorKind::ValueOutOfRange(value)));
+                        }
+                        pc = value as usize;
+                        continue;
+                    }
+                    "byte" => Statement::Byte(parse_list(rest).map_err(error)?),
+                    "word" => Statement::Word(parse_list(rest).map_err(error)?),
+                    _ => {
+                        return Err(error(AssemblerErrorKind::UnknownDirective(
+                            keyword.to_string(),
+                        )))
+                    }
+                }
+            } else {
+                let name = self.find_mnemonic(keyword).ok_or_else(|| {
+                    error(AssemblerErrorKind::UnknownMnemonic(keyword.to_string()))
+                })?;
+                let operand = Operand::parse(rest).map_err(error)?;
+                let value = match operand.expr() {
+                    Some(expr) => expr.evaluate(&labels, pc as u16).map_err(error)?,
+                    None => None,
+                };
+                let mode = self.select_mode(name, &operand, value).map_err(error)?;
+                Statement::Instruction {
+                    name,
+                    mode,
+                    operand: operand.expr().cloned(),
+                }
+            };
+
+            let size = statement_size(&statement);
+            if pc + size > MEM_SIZE {
+                return Err(error(AssemblerErrorKind::AddressOverflow));
+            }
+            statements.push(Located {
+                line,
+                address: pc as u16,
+                statement,
+            });
+            pc += size;
+        }
+
+        Ok((statements, labels))
+    }
+
+    fn find_mnemonic(&self, mnemonic: &str) -> Option<InstructionName> {
+        let mnemonic = mnemonic.to_lowercase();
+        self.registry
+            .all_instructions
+            .iter()
+            .find(|builder| builder.name.to_string() == mnemonic)
+            .map(|builder| builder.name)
+    }
+
+    fn select_mode(
+        &self,
+        name: InstructionName,
+        operand: &Operand,
+        value: Option<i64>,
+    ) -> Result<AddressingMode, AssemblerErrorKind> {
+        let modes = self.registry.get_instruction_by_name(name).get_modes();
+        let supports = |mode: AddressingMode| {
+            if modes.contains_key(&mode) {
+                Ok(mode)
+            } else {
+                Err(AssemblerErrorKind::UnsupportedAddressingMode(name, mode))
+            }
+        };
+        let zero_page_or_absolute = |zero_page: AddressingMode, absolute: AddressingMode| {
+            let fits_zero_page = matches!(value, Some(value) if (0..=0xff).contains(&value));
+            if modes.contains_key(&zero_page) && (fits_zero_page || !modes.contains_key(&absolute))
+            {
+                Ok(zero_page)
+            } else {
+                supports(absolute)
+            }
+        };
+
+        match operand {
+            Operand::None => {
+                if modes.contains_key(&AddressingMode::Implicit) {
+                    Ok(AddressingMode::Implicit)
+                } else if modes.contains_key(&AddressingMode::Accumulator) {
+                    Ok(AddressingMode::Accumulator)
+                } else {
+                    Err(AssemblerErrorKind::MissingOperand(name))
+                }
+            }
+            Operand::Accumulator => supports(AddressingMode::Accumulator),
+            Operand::Immediate(_) => supports(AddressingMode::Immediate),
+            Operand::Direct(_) => {
+                if modes.contains_key(&AddressingMode::Relative) {
+                    Ok(AddressingMode::Relative)
+                } else {
+                    zero_page_or_absolute(AddressingMode::ZeroPage, AddressingMode::Absolute)
+                }
+            }
+            Operand::DirectX(_) => {
+                zero_page_or_absolute(AddressingMode::ZeroPageX, AddressingMode::AbsoluteX)
+            }
+            Operand::DirectY(_) => {
+                zero_page_or_absolute(AddressingMode::ZeroPageY, AddressingMode::AbsoluteY)
+            }
+            Operand::Indirect(_) => supports(AddressingMode::Indirect),
+            Operand::IndirectX(_) => supports(AddressingMode::IndirectX),
+            Operand::IndirectY(_) => supports(AddressingMode::IndirectY),
+        }
+    }
+
+    fn encode(
+        &self,
+        located: &Located,
+        labels: &HashMap<String, u16>,
+    ) -> Result<Vec<u8>, AssemblerErrorKind> {
+        let pc = located.address;
+        match &located.statement {
+            Statement::Byte(exprs) => exprs
+                .iter()
+                .map(|expr| {
+                    let value = expr.resolve(labels, pc)?;
+                    check_range(value, -0x80, 0xff).map(|value| value as u8)
+                })
+                .collect(),
+            Statement::Word(exprs) => {
+                let mut bytes = Vec::with_capacity(exprs.len() * 2);
+                for expr in exprs {
+                    let value = check_range(expr.resolve(labels, pc)?, -0x8000, 0xffff)? as u16;
+                    bytes.push((value & 0xff) as u8);
+                    bytes.push((value >> 8) as u8);
+                }
+                Ok(bytes)
+            }
+            Statement::Instruction {
+                name,
+                mode,
+                operand,
+            } => {
+                let value = match operand {
+                    Some(expr) => expr.resolve(labels, pc)?,
+                    None => 0,
+                };
+                let operand = match mode {
+                    AddressingMode::Implicit | AddressingMode::Accumulator => 0,
+                    AddressingMode::Relative => {
+                        let offset = value - (pc as i64 + 2);
+                        if !(-0x80..=0x7f).contains(&offset) {
+                            return Err(AssemblerErrorKind::BranchOutOfRange(offset));
+                        }
+                        offset as u8 as u16
+                    }
+                    AddressingMode::Immediate => check_range(value, -0x80, 0xff)? as u8 as u16,
+                    _ if mode.operand_size() == 1 => check_range(value, 0, 0xff)? as u16,
+                    _ => check_range(value, 0, 0xffff)? as u16,
+                };
+
+                let instruction = self
+                    .registry
+                    .get_instruction_by_name(*name)
+                    .build(*mode, operand)
+                    .ok_or(AssemblerErrorKind::UnsupportedAddressingMode(*name, *mode))?;
+                let mut bytes = vec![instruction.op_code];
+                match mode.operand_size() {
+                    0 => {}
+                    1 => bytes.push(operand as u8),
+                    _ => {
+                        bytes.push((operand & 0xff) as u8);
+                        bytes.push((operand >> 8) as u8);
+                    }
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn assemble(source: &str) -> Result<[u8; MEM_SIZE], AssemblerError> {
+    Ok(Assembler::new().assemble(source)?.image)
+}
+
+fn statement_size(statement: &Statement) -> usize {
+    match statement {
+        Statement::Byte(exprs) => exprs.len(),
+        Statement::Word(exprs) => exprs.len() * 2,
+        Statement::Instruction { mode, .. } => 1 + mode.operand_size() as usize,
+    }
+}
+
+fn parse_list(text: &str) -> Result<Vec<Expr>, AssemblerErrorKind> {
+    text.split(',').map(Expr::parse).collect()
+}
+
+fn check_range(value: i64, min: i64, max: i64) -> Result<i64, AssemblerErrorKind> {
+    if (min..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(AssemblerErrorKind::ValueOutOfRange(value))
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses a number written `$ff`, `0xff`, `%101`, `0b101` or `255`. Breakpoint conditions use
+/// the same notation.
+pub(crate) fn parse_number(text: &str) -> Option<i64> {
+    let (digits, radix) = if let Some(hex) = text.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        (hex, 16)
+    } else if let Some(bin) = text.strip_prefix('%').or(text.strip_prefix("0b")) {
+        (bin, 2)
+    } else {
+        (text, 10)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_assembles(source: &str, address: usize, expected: &[u8]) {
+        let image = assemble(source).unwrap();
+        assert_eq!(
+            &image[address..address + expected.len()],
+            expected,
+            "{source}"
+        );
+    }
+
+    fn assemble_error(source: &str) -> AssemblerError {
+        assemble(source).err().unwrap()
+    }
+
+    #[test]
+    fn forward_references_are_resolved() {
+        let source = ".org $0200\njmp target\nbeq target\nnop\ntarget: rts";
+        assert_assembles(source, 0x0200, &[0x4c, 0x06, 0x02, 0xf0, 0x01, 0xea, 0x60]);
+    }
+
+    #[test]
+    fn zero_page_is_used_when_the_address_fits() {
+        assert_assembles("lda $10", 0, &[0xa5, 0x10]);
+        assert_assembles("lda $1234", 0, &[0xad, 0x34, 0x12]);
+        assert_assembles("lda $10,x", 0, &[0xb5, 0x10]);
+        assert_assembles("lda $1234,x", 0, &[0xbd, 0x34, 0x12]);
+        assert_assembles("ldx $10,y", 0, &[0xb6, 0x10]);
+        // lda has no zero page,y mode
+        assert_assembles("lda $10,y", 0, &[0xb9, 0x10, 0x00]);
+    }
+
+    #[test]
+    fn directives_place_data() {
+        let source = ".org $0300\n.byte 1, $ff, %101, <end, >end\n.word $1234, end\nend:";
+        assert_assembles(
+            source,
+            0x0300,
+            &[0x01, 0xff, 0x05, 0x09, 0x03, 0x34, 0x12, 0x09, 0x03],
+        );
+    }
+
+    #[test]
+    fn unknown_mnemonic_is_an_error() {
+        let error = assemble_error("nop\nfoo $10");
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.kind,
+            AssemblerErrorKind::UnknownMnemonic("foo".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_operand_is_an_error() {
+        let error = assemble_error("lda");
+        assert_eq!(error.line, 1);
+        assert_eq!(
+            error.kind,
+            AssemblerErrorKind::MissingOperand(InstructionName::lda)
+        );
+    }
+
+    #[test]
+    fn out_of_range_branch_is_an_error() {
+        let error = assemble_error(".org 0\nbne far\n.org $0200\nfar: rts");
+        assert_eq!(error.line, 2);
+        assert_eq!(error.kind, AssemblerErrorKind::BranchOutOfRange(0x01fe));
+    }
+
+    #[test]
+    fn duplicate_label_is_an_error() {
+        let error = assemble_error("loop: nop\nloop: nop");
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.kind,
+            AssemblerErrorKind::DuplicateLabel("loop".to_string())
+        );
+    }
+}
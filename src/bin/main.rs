@@ -1,42 +1,390 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use std::{
+    cell::RefCell,
     fmt::Display,
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    rc::Rc,
     str::FromStr,
 };
 
-use micro_6502::emulator::Emulator;
+use micro_6502::assembler::{Assembler, Program};
+use micro_6502::breakpoint::BreakpointKind;
+use micro_6502::callstack::Backtrace;
+use micro_6502::cfg::ControlFlowGraph;
+use micro_6502::coverage::Coverage;
+use micro_6502::diff::diff_trace;
+use micro_6502::disassembler::Disassembly;
+use micro_6502::emulator::{Emulator, StopReason};
+use micro_6502::expr::{Expression, Node};
 use micro_6502::mem::{Memory, MEM_SIZE};
+use micro_6502::profiler::Profiler;
 use micro_6502::regs::{CpuFlags, Regs};
-use std::fs::read;
+use micro_6502::trace::TraceFormat;
+use std::fs::{create_dir_all, read, read_to_string, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process::exit;
 
 fn main() {
     let args = Args::parse();
 
-    let mut emulator = {
+    match &args.command {
+        Some(Command::Diff(diff_args)) => {
+            diff(diff_args);
+            return;
+        }
+        Some(Command::Disassemble(disassemble_args)) => {
+            disassemble(disassemble_args);
+            return;
+        }
+        Some(Command::Cfg(cfg_args)) => {
+            cfg(cfg_args);
+            return;
+        }
+        None => {}
+    }
+    let path = args.path.as_ref().unwrap();
+    let (memory, program) = load_program(path);
+    let mut emulator = Emulator::new(memory);
+    *emulator.get_regs_mut() = args.regs.regs.clone();
+    if let Some(trace_path) = &args.trace {
+        let file = File::create(trace_path)
+            .unwrap_or_else(|_| panic!("Cannot create {}", trace_path.display()));
+        emulator.enable_tracing(Box::new(BufWriter::new(file)), args.trace_format);
+    }
+    let profiler = if args.profile.is_some() || args.profile_stacks.is_some() {
+        let mut profiler = Profiler::new();
+        if let Some(program) = &program {
+            profiler.set_labels(&program.labels);
+        }
+        let profiler = Rc::new(RefCell::new(profiler));
+        emulator.add_observer(Box::new(profiler.clone()));
+        Some(profiler)
+    } else {
+        None
+    };
+    let coverage = if args.coverage.is_some() || args.lcov.is_some() {
+        let coverage = Rc::new(RefCell::new(Coverage::new()));
+        emulator.add_observer(Box::new(coverage.clone()));
+        Some(coverage)
+    } else {
+        None
+    };
+    for breakpoint in &args.breakpoints {
+        let kind = BreakpointKind::Address(breakpoint.address);
+        let breakpoints = emulator.get_breakpoints_mut();
+        match &breakpoint.condition {
+            Some(condition) => breakpoints.add_conditional(kind, condition.clone()),
+            None => breakpoints.add(kind),
+        };
+    }
+    let result = if args.max_cycles.is_some()
+        || args.max_instructions.is_some()
+        || !args.breakpoints.is_empty()
+    {
+        let reset_addr = emulator.get_reset_addr();
+        emulator.get_regs_mut().pc = reset_addr;
+        match (args.max_cycles, args.max_instructions) {
+            (Some(max_cycles), _) => emulator.run_for_cycles(max_cycles),
+            (None, Some(max_instructions)) => emulator.run_for_instructions(max_instructions),
+            (None, None) => emulator.run_until(|_| false),
+        }
+        .map(Some)
+    } else {
+        emulator.try_run_until_break().map(|_| None)
+    };
+    if let Some(mut tracer) = emulator.disable_tracing() {
+        if let Some(err) = tracer.take_error().or_else(|| tracer.flush().err()) {
+            eprintln!("Cannot write the trace: {err}");
+        }
+    }
+    if let Some(profiler) = profiler {
+        let profiler = profiler.borrow();
+        if let Some(profile_path) = &args.profile {
+            write_report(profile_path, |file| profiler.write_report(file));
+        }
+        if let Some(stacks_path) = &args.profile_stacks {
+            write_report(stacks_path, |file| profiler.write_collapsed_stacks(file));
+        }
+    }
+    if let Some(coverage) = coverage {
+        let coverage = coverage.borrow();
+        let bus = emulator.get_bus();
+        if let Some(coverage_path) = &args.coverage {
+            write_report(coverage_path, |file| coverage.write_listing(bus, file));
+        }
+        match (&args.lcov, &program) {
+            (Some(lcov_path), Some(program)) => write_report(lcov_path, |file| {
+                let source_path = path.to_string_lossy();
+                coverage.write_lcov(&source_path, &program.listing, bus, file)
+            }),
+            (Some(_), None) => eprintln!("LCOV needs an assembly source to map addresses to lines"),
+            (None, _) => {}
+        }
+    }
+    for warning in emulator.get_call_stack_mut().take_warnings() {
+        eprintln!("Warning: {warning}");
+    }
+    println!("{}", emulator.get_regs());
+    match result {
+        Ok(Some(StopReason::BudgetExhausted)) => {
+            eprintln!("Stopped: {}", StopReason::BudgetExhausted);
+            exit(2);
+        }
+        Ok(Some(StopReason::Breakpoint(id))) => {
+            let breakpoint = emulator.get_breakpoints().get(id).unwrap();
+            eprintln!("Stopped: breakpoint {id} ({breakpoint})");
+        }
+        Ok(Some(reason)) => eprintln!("Stopped: {reason}"),
+        Ok(None) => {}
+        Err(err) => {
+            eprintln!("Error: {err}");
+            // The program counter has already moved past the faulting instruction
+            let backtrace = Backtrace {
+                pc: err.pc,
+                ..emulator.backtrace()
+            };
+            let backtrace = match &program {
+                Some(program) => backtrace.format_with_labels(&program.labels),
+                None => backtrace.to_string(),
+            };
+            eprintln!("Backtrace:\n{backtrace}");
+            exit(1);
+        }
+    }
+}
+
+// The program is only known for assembly sources
+fn load_program(path: &Path) -> (Memory, Option<Program>) {
+    if path.extension().is_some_and(|ext| ext == "asm") {
+        let source =
+            read_to_string(path).unwrap_or_else(|_| panic!("Cannot find {}", path.display()));
+        let program = Assembler::new()
+            .assemble(&source)
+            .unwrap_or_else(|err| panic!("Cannot assemble {}: {err}", path.display()));
+        (Memory::new_from_bytes(program.image), Some(program))
+    } else {
         let memory_bytes_vec =
-            read(&args.path).expect(format!("Cannot find {}", args.path.display()).as_str());
+            read(path).unwrap_or_else(|_| panic!("Cannot find {}", path.display()));
         let memory_bytes: [u8; MEM_SIZE] = memory_bytes_vec
             .try_into()
-            .expect(format!("Inputted file must be {MEM_SIZE} bytes.").as_str());
-        let memory = Memory::new_from_bytes(memory_bytes);
-        Emulator::new(Box::new(memory))
-    };
-    *emulator.get_regs_mut() = args.regs.regs.clone();
-    emulator.run_until_break();
-    println!("{}", emulator.get_regs());
+            .unwrap_or_else(|_| panic!("Inputted file must be {MEM_SIZE} bytes."));
+        (Memory::new_from_bytes(memory_bytes), None)
+    }
+}
+
+fn write_report<F: FnOnce(&mut BufWriter<File>) -> io::Result<()>>(path: &Path, write: F) {
+    let result = File::create(path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.flush()
+    });
+    if let Err(err) = result {
+        eprintln!("Cannot write {}: {err}", path.display());
+    }
+}
+
+fn diff(args: &DiffArgs) {
+    let (memory, _) = load_program(&args.path);
+    let mut emulator = Emulator::new(memory);
+    *emulator.get_regs_mut() = args.regs.regs;
+    let start = args.start.unwrap_or_else(|| emulator.get_reset_addr());
+    emulator.get_regs_mut().pc = start;
+    let reference = File::open(&args.reference)
+        .unwrap_or_else(|_| panic!("Cannot find {}", args.reference.display()));
+    match diff_trace(
+        &mut emulator,
+        BufReader::new(reference),
+        args.format,
+        args.context,
+    ) {
+        Ok(None) => println!("No divergence"),
+        Ok(Some(divergence)) => {
+            print!("{divergence}");
+            exit(2);
+        }
+        Err(err) => {
+            eprintln!("Error: {err}");
+            exit(1);
+        }
+    }
+}
+
+fn disassemble(args: &DisassembleArgs) {
+    let (memory, _) = load_program(&args.path);
+    let disassembly = Disassembly::new(&memory, &args.entry_points);
+    match &args.output {
+        Some(path) => write_report(path, |file| disassembly.write_source(&memory, file)),
+        None => {
+            if let Err(err) = disassembly.write_source(&memory, io::stdout().lock()) {
+                eprintln!("Error: {err}");
+                exit(1);
+            }
+        }
+    }
+}
+
+fn cfg(args: &CfgArgs) {
+    let (memory, _) = load_program(&args.path);
+    let graph = ControlFlowGraph::new(&Disassembly::new(&memory, &args.entry_points));
+    if let Some(dir) = &args.per_subroutine {
+        if let Err(err) = create_dir_all(dir) {
+            eprintln!("Cannot create {}: {err}", dir.display());
+            exit(1);
+        }
+        for (&entry, subroutine) in graph.get_subroutines() {
+            let path = dir.join(format!("{}.dot", subroutine.name.trim_start_matches('$')));
+            write_report(&path, |file| graph.write_subroutine_dot(entry, file));
+        }
+        return;
+    }
+    match &args.output {
+        Some(path) => write_report(path, |file| graph.write_dot(file)),
+        None => {
+            if let Err(err) = graph.write_dot(io::stdout().lock()) {
+                eprintln!("Error: {err}");
+                exit(1);
+            }
+        }
+    }
 }
 
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 pub struct Args {
-    /// The path to the memory binary to initialize the CPU with
-    pub path: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// The path to the memory binary to initialize the CPU with.
+    /// Files ending in .asm are assembled before running
+    #[arg(required = true)]
+    pub path: Option<PathBuf>,
     /// Initialize the CPU registers
     /// Example: --regs x=3,y=2
     #[arg(long, default_value_t)]
     pub regs: RegsArg,
+    /// Stop after this many cycles instead of running until brk, exiting with status 2
+    #[arg(long, conflicts_with = "max_instructions")]
+    pub max_cycles: Option<u64>,
+    /// Stop after this many instructions instead of running until brk, exiting with status 2
+    #[arg(long)]
+    pub max_instructions: Option<u64>,
+    /// Stop before the instruction at an address, optionally only when a condition holds
+    /// Example: --break '$0210 if a == $10 && [$00f7] > 3 && !C'
+    #[arg(long = "break", value_name = "ADDR[ if EXPR]")]
+    pub breakpoints: Vec<BreakArg>,
+    /// Write a line per executed instruction to this file
+    #[arg(long)]
+    pub trace: Option<PathBuf>,
+    /// The layout of the trace: nestest or json
+    #[arg(long, default_value_t, requires = "trace")]
+    pub trace_format: TraceFormat,
+    /// Write the cycles spent per subroutine and per address to this file
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+    /// Write the cycles spent per call stack to this file, for flamegraph tools
+    #[arg(long)]
+    pub profile_stacks: Option<PathBuf>,
+    /// Write a disassembly of the executed, read and written bytes with execution and branch
+    /// counts to this file
+    #[arg(long)]
+    pub coverage: Option<PathBuf>,
+    /// Write line and branch coverage of the assembly source to this file in LCOV format
+    #[arg(long)]
+    pub lcov: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run a program in lockstep with a reference trace and report the first instruction where
+    /// the PC, registers, flags or cycle count differ, exiting with status 2
+    Diff(DiffArgs),
+    /// Disassemble a program by following its control flow from the reset, NMI and IRQ vectors,
+    /// into assembly source that assembles to the same image
+    Disassemble(DisassembleArgs),
+    /// Write the control-flow graph of a program in Graphviz DOT format, with the basic blocks
+    /// grouped into subroutines
+    Cfg(CfgArgs),
+}
+
+#[derive(clap::Args)]
+pub struct DiffArgs {
+    /// The path to the memory binary or .asm source to run
+    pub path: PathBuf,
+    /// The trace of a known-good emulator, one line per instruction
+    pub reference: PathBuf,
+    /// The layout of the reference trace: nestest or json
+    #[arg(long, default_value_t)]
+    pub format: TraceFormat,
+    /// Start at this address instead of the reset vector
+    #[arg(long, value_parser = parse_address)]
+    pub start: Option<u16>,
+    /// Initialize the CPU registers
+    #[arg(long, default_value_t)]
+    pub regs: RegsArg,
+    /// How many instructions to show before and after the divergence
+    #[arg(long, default_value_t = 5)]
+    pub context: usize,
+}
+
+#[derive(clap::Args)]
+pub struct DisassembleArgs {
+    /// The path to the memory binary or .asm source to disassemble
+    pub path: PathBuf,
+    /// Also follow the control flow from this address
+    #[arg(long = "entry", value_name = "ADDR", value_parser = parse_address)]
+    pub entry_points: Vec<u16>,
+    /// Write the source to this file instead of the standard output
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+pub struct CfgArgs {
+    /// The path to the memory binary or .asm source to graph
+    pub path: PathBuf,
+    /// Also follow the control flow from this address
+    #[arg(long = "entry", value_name = "ADDR", value_parser = parse_address)]
+    pub entry_points: Vec<u16>,
+    /// Write the graph to this file instead of the standard output
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+    /// Write a graph per subroutine to this directory instead, named after the subroutine
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    pub per_subroutine: Option<PathBuf>,
+}
+
+fn parse_address(s: &str) -> Result<u16, String> {
+    match Expression::parse(s)
+        .map_err(|err| err.to_string())?
+        .get_root()
+    {
+        Node::Number(address) if (0..=0xffff).contains(address) => Ok(*address as u16),
+        _ => Err(format!("Not a valid address: {s}")),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakArg {
+    pub address: u16,
+    pub condition: Option<Expression>,
+}
+
+impl FromStr for BreakArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, condition) = match s.split_once(" if ") {
+            Some((address, condition)) => (address, Some(condition)),
+            None => (s, None),
+        };
+        let address = parse_address(address)?;
+        let condition = condition
+            .map(Expression::parse)
+            .transpose()
+            .map_err(|err| format!("Cannot parse condition: {err}"))?;
+        Ok(Self { address, condition })
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -0,0 +1,204 @@
+use std::fmt::{Display, Formatter};
+
+use crate::expr::Expression;
+use crate::instruction::InstructionName;
+use crate::readwritable::ReadWritable;
+use crate::regs::Regs;
+
+pub type BreakpointId = usize;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// Stops before the instruction at this address executes
+    Address(u16),
+    /// Stops before any instruction with this op code executes
+    OpCode(u8),
+    /// Stops before any instruction with this name executes
+    Instruction(InstructionName),
+    /// Stops after an instruction reads a byte in `start..=end`
+    Read { start: u16, end: u16 },
+    /// Stops after an instruction writes a byte in `start..=end`
+    Write { start: u16, end: u16 },
+}
+
+impl BreakpointKind {
+    pub fn is_watchpoint(&self) -> bool {
+        matches!(
+            self,
+            BreakpointKind::Read { .. } | BreakpointKind::Write { .. }
+        )
+    }
+}
+
+impl Display for BreakpointKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BreakpointKind::Address(addr) => write!(f, "address {addr:#06x}"),
+            BreakpointKind::OpCode(op_code) => write!(f, "op code {op_code:#04x}"),
+            BreakpointKind::Instruction(name) => write!(f, "instruction {name}"),
+            BreakpointKind::Read { start, end } => {
+                write!(f, "read of {start:#06x}..={end:#06x}")
+            }
+            BreakpointKind::Write { start, end } => {
+                write!(f, "write of {start:#06x}..={end:#06x}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub kind: BreakpointKind,
+    /// Only fires when this holds. Watchpoint conditions see the state before the access.
+    pub condition: Option<Expression>,
+    pub enabled: bool,
+    /// How many times the breakpoint fired
+    pub hits: u64,
+}
+
+impl Display for Breakpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {condition}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    breakpoints: Vec<Option<Breakpoint>>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, kind: BreakpointKind) -> BreakpointId {
+        self.add_breakpoint(kind, None)
+    }
+
+    pub fn add_conditional(&mut self, kind: BreakpointKind, condition: Expression) -> BreakpointId {
+        self.add_breakpoint(kind, Some(condition))
+    }
+
+    fn add_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        condition: Option<Expression>,
+    ) -> BreakpointId {
+        self.breakpoints.push(Some(Breakpoint {
+            kind,
+            condition,
+            enabled: true,
+            hits: 0,
+        }));
+        self.breakpoints.len() - 1
+    }
+
+    pub fn remove(&mut self, id: BreakpointId) -> Option<Breakpoint> {
+        self.breakpoints.get_mut(id)?.take()
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn get(&self, id: BreakpointId) -> Option<&Breakpoint> {
+        self.breakpoints.get(id)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: BreakpointId) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(id)?.as_mut()
+    }
+
+    /// Returns false if there is no breakpoint with this id
+    pub fn set_enabled(&mut self, id: BreakpointId, enabled: bool) -> bool {
+        match self.get_mut(id) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (BreakpointId, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(id, breakpoint)| Some((id, breakpoint.as_ref()?)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Counts a hit on every enabled breakpoint matching the instruction about to execute and
+    /// returns the first of them. `name` is `None` for illegal op codes.
+    pub fn check_execution(
+        &mut self,
+        address: u16,
+        op_code: u8,
+        name: Option<InstructionName>,
+        regs: &Regs,
+        bus: &dyn ReadWritable,
+    ) -> Option<BreakpointId> {
+        self.check(regs, bus, |kind| match *kind {
+            BreakpointKind::Address(addr) => addr == address,
+            BreakpointKind::OpCode(code) => code == op_code,
+            BreakpointKind::Instruction(ins_name) => Some(ins_name) == name,
+            BreakpointKind::Read { .. } | BreakpointKind::Write { .. } => false,
+        })
+    }
+
+    /// Counts a hit on every enabled watchpoint covering the access and returns the first of them
+    pub fn check_access(
+        &mut self,
+        access: Access,
+        address: u16,
+        regs: &Regs,
+        bus: &dyn ReadWritable,
+    ) -> Option<BreakpointId> {
+        self.check(regs, bus, |kind| match (*kind, access) {
+            (BreakpointKind::Read { start, end }, Access::Read)
+            | (BreakpointKind::Write { start, end }, Access::Write) => {
+                (start..=end).contains(&address)
+            }
+            _ => false,
+        })
+    }
+
+    fn check<F: Fn(&BreakpointKind) -> bool>(
+        &mut self,
+        regs: &Regs,
+        bus: &dyn ReadWritable,
+        matches: F,
+    ) -> Option<BreakpointId> {
+        let mut fired = None;
+        for (id, slot) in self.breakpoints.iter_mut().enumerate() {
+            let Some(breakpoint) = slot else {
+                continue;
+            };
+            if !breakpoint.enabled || !matches(&breakpoint.kind) {
+                continue;
+            }
+            if let Some(condition) = &breakpoint.condition {
+                if !condition.is_true(regs, bus) {
+                    continue;
+                }
+            }
+            breakpoint.hits += 1;
+            fired = fired.or(Some(id));
+        }
+        fired
+    }
+}
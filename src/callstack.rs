@@ -0,0 +1,218 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::vec::Drain;
+
+use crate::emulator::InterruptKind;
+
+/// How many warnings are kept, the oldest are dropped first
+pub const MAX_CALL_STACK_WARNINGS: usize = 64;
+/// How many frames are kept, the outermost are dropped first. The hardware stack holds at most
+/// 128 return addresses, so the return addresses of deeper frames have been overwritten.
+pub const MAX_CALL_STACK_DEPTH: usize = 128;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameKind {
+    Subroutine,
+    Interrupt(InterruptKind),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: FrameKind,
+    /// Where the subroutine or interrupt handler starts
+    pub entry: u16,
+    /// The address of the `jsr` or `brk`, or of the instruction an interrupt was taken before
+    pub call_site: u16,
+    /// Where execution continues once the frame returns
+    pub return_addr: u16,
+    /// The stack pointer just after the return address, and the flags for interrupts, were
+    /// pushed
+    pub sp: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CallStackWarning {
+    /// An `rts` or `rti` at `pc` returned to `target`, which no frame on the call stack returns
+    /// to. This is how jump tables built on `rts` look too.
+    UnexpectedReturn { pc: u16, target: u16 },
+    /// An `rts` or `rti` at `pc` returned past the `skipped` innermost frames, e.g. after the
+    /// return address of a subroutine was discarded with `pla`
+    SkippedFrames { pc: u16, skipped: usize },
+    /// A `txs` at `pc` moved the stack pointer above the return addresses of the `discarded`
+    /// innermost frames
+    StackPointerMoved { pc: u16, discarded: usize },
+}
+
+/// How an instruction changed the call stack, enough to revert it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallStackChange {
+    /// A frame was pushed, dropping this outermost frame if the stack was full
+    Pushed(Option<CallFrame>),
+    /// These frames were popped, outermost first
+    Popped(Vec<CallFrame>),
+}
+
+impl Display for CallStackWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallStackWarning::UnexpectedReturn { pc, target } => write!(
+                f,
+                "return at {pc:#06x} to {target:#06x}, which is not a return site"
+            ),
+            CallStackWarning::SkippedFrames { pc, skipped } => {
+                write!(f, "return at {pc:#06x} skipped {skipped} frames")
+            }
+            CallStackWarning::StackPointerMoved { pc, discarded } => write!(
+                f,
+                "txs at {pc:#06x} discarded the return addresses of {discarded} frames"
+            ),
+        }
+    }
+}
+
+/// A call stack kept alongside the hardware stack from `jsr`, `rts`, interrupts and `rti`.
+/// Returns that do not match it are recorded as warnings instead of corrupting it.
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+    warnings: VecDeque<CallStackWarning>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Outermost first
+    pub fn get_frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn get_warnings(&self) -> impl DoubleEndedIterator<Item = &CallStackWarning> {
+        self.warnings.iter()
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<CallStackWarning> {
+        self.warnings.drain(..).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.warnings.clear();
+    }
+
+    /// Pushes `frame` and returns the outermost frame if it had to be dropped to stay within
+    /// `MAX_CALL_STACK_DEPTH`
+    pub fn push(&mut self, frame: CallFrame) -> Option<CallFrame> {
+        let dropped = if self.frames.len() == MAX_CALL_STACK_DEPTH {
+            Some(self.frames.remove(0))
+        } else {
+            None
+        };
+        self.frames.push(frame);
+        dropped
+    }
+
+    /// Pops the frame that returns to `target`, along with every frame above it. The popped
+    /// frames are removed when the returned iterator is dropped.
+    pub fn pop(&mut self, pc: u16, target: u16) -> Drain<'_, CallFrame> {
+        let Some(index) = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.return_addr == target)
+        else {
+            self.warn(CallStackWarning::UnexpectedReturn { pc, target });
+            return self.frames.drain(self.frames.len()..);
+        };
+        let skipped = self.frames.len() - index - 1;
+        if skipped > 0 {
+            self.warn(CallStackWarning::SkippedFrames { pc, skipped });
+        }
+        self.frames.drain(index..)
+    }
+
+    /// Drops the frames whose return addresses are no longer on the stack once the stack
+    /// pointer is `sp`. Like `pop`, they are removed when the returned iterator is dropped.
+    pub fn set_stack_pointer(&mut self, pc: u16, sp: u8) -> Drain<'_, CallFrame> {
+        // The return address of a frame is in the two bytes above its stack pointer
+        let index = self
+            .frames
+            .iter()
+            .rposition(|frame| (sp as u16) < frame.sp as u16 + 2)
+            .map_or(0, |index| index + 1);
+        let discarded = self.frames.len() - index;
+        if discarded > 0 {
+            self.warn(CallStackWarning::StackPointerMoved { pc, discarded });
+        }
+        self.frames.drain(index..)
+    }
+
+    /// Undoes `change`, which must be the last change made to the frames
+    pub fn revert(&mut self, change: CallStackChange) {
+        match change {
+            CallStackChange::Pushed(dropped) => {
+                self.frames.pop();
+                if let Some(frame) = dropped {
+                    self.frames.insert(0, frame);
+                }
+            }
+            CallStackChange::Popped(frames) => self.frames.extend(frames),
+        }
+    }
+
+    fn warn(&mut self, warning: CallStackWarning) {
+        if self.warnings.len() == MAX_CALL_STACK_WARNINGS {
+            self.warnings.pop_front();
+        }
+        self.warnings.push_back(warning);
+    }
+}
+
+/// Where execution is and how it got there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backtrace {
+    pub pc: u16,
+    /// Outermost first
+    pub frames: Vec<CallFrame>,
+}
+
+impl Backtrace {
+    /// Formats the backtrace like `Display`, naming entry points after `labels`, e.g.
+    /// `Program::labels`
+    pub fn format_with_labels(&self, labels: &HashMap<String, u16>) -> String {
+        let mut names = labels.iter().collect::<Vec<_>>();
+        names.sort();
+        let name = |address: u16| {
+            names
+                .iter()
+                .find(|(_, &label_addr)| label_addr == address)
+                .map_or_else(|| format!("${address:04x}"), |(name, _)| name.to_string())
+        };
+        self.format(name)
+    }
+
+    // Innermost first, the way debuggers print backtraces
+    fn format<F: Fn(u16) -> String>(&self, name: F) -> String {
+        let mut lines = Vec::with_capacity(self.frames.len() + 1);
+        let mut pc = self.pc;
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            let interrupt = match frame.kind {
+                FrameKind::Subroutine => String::new(),
+                FrameKind::Interrupt(kind) => format!(" ({kind:?} handler)"),
+            };
+            lines.push(format!(
+                "#{depth} ${pc:04x} in {}{interrupt}",
+                name(frame.entry)
+            ));
+            pc = frame.call_site;
+        }
+        lines.push(format!("#{} ${pc:04x}", self.frames.len()));
+        lines.join("\n")
+    }
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format(|address| format!("${address:04x}")))
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use crate::disassembler::Disassembly;
+use crate::instruction::{AddressingMode, Instruction, InstructionName};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    /// To the next instruction, including the return site of a `jsr`
+    Fallthrough,
+    /// To the target of a branch, when it is taken
+    Branch,
+    Jump,
+    /// From a `jsr` to the subroutine
+    Call,
+    /// From an `rts` to the return sites of the calls to its subroutine
+    Return,
+}
+
+impl EdgeKind {
+    // Graphviz attributes that tell the kinds apart
+    fn get_attributes(&self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "",
+            EdgeKind::Branch => "color=darkgreen, label=\"taken\"",
+            EdgeKind::Jump => "color=blue",
+            EdgeKind::Call => "color=purple, style=dashed, label=\"call\"",
+            EdgeKind::Return => "color=gray, style=dotted, label=\"return\"",
+        }
+    }
+}
+
+/// Between the start addresses of two basic blocks
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    pub from: u16,
+    pub to: u16,
+    pub kind: EdgeKind,
+}
+
+/// Instructions that always execute one after the other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub instructions: Vec<(u16, Instruction)>,
+}
+
+impl BasicBlock {
+    /// The address just after the last instruction
+    pub fn get_end(&self) -> u16 {
+        let (address, instruction) = self.instructions.last().unwrap();
+        address.wrapping_add(instruction.size())
+    }
+}
+
+/// The blocks reachable from an entry point or `jsr` target without following calls and
+/// returns. A block may belong to several subroutines when they share code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subroutine {
+    pub entry: u16,
+    pub name: String,
+    pub blocks: BTreeSet<u16>,
+}
+
+/// The basic blocks of the code found by a `Disassembly`, the edges between them, and the
+/// subroutines they make up
+#[derive(Debug, Clone)]
+pub struct ControlFlowGraph {
+    blocks: BTreeMap<u16, BasicBlock>,
+    edges: BTreeSet<Edge>,
+    subroutines: BTreeMap<u16, Subroutine>,
+    labels: BTreeMap<u16, String>,
+}
+
+impl ControlFlowGraph {
+    pub fn new(disassembly: &Disassembly) -> Self {
+        let instructions = disassembly.get_instructions();
+        let mut graph = Self {
+            blocks: BTreeMap::new(),
+            edges: BTreeSet::new(),
+            subroutines: BTreeMap::new(),
+            labels: disassembly.get_labels().clone(),
+        };
+
+        // Blocks start at every label, which covers the entry points and the targets, and
+        // after every instruction that can go anywhere but the next one
+        let mut block: Option<BasicBlock> = None;
+        for (&address, &instruction) in instructions {
+            let starts_block = match &block {
+                Some(block) => block.get_end() != address || graph.labels.contains_key(&address),
+                None => true,
+            };
+            if starts_block {
+                if let Some(block) = block.take() {
+                    graph.blocks.insert(block.start, block);
+                }
+                block = Some(BasicBlock {
+                    start: address,
+                    instructions: Vec::new(),
+                });
+            }
+            let current = block.as_mut().unwrap();
+            current.instructions.push((address, instruction));
+            if ends_block(&instruction) {
+                graph.blocks.insert(current.start, block.take().unwrap());
+            }
+        }
+        if let Some(block) = block {
+            graph.blocks.insert(block.start, block);
+        }
+
+        for block in graph.blocks.values() {
+            let &(address, instruction) = block.instructions.last().unwrap();
+            let next = block.get_end();
+            let mut edges = Vec::new();
+            let target = get_target(address, &instruction);
+            match (instruction.name, instruction.addressing_mode) {
+                (_, AddressingMode::Relative) => {
+                    edges.push((target.unwrap(), EdgeKind::Branch));
+                    edges.push((next, EdgeKind::Fallthrough));
+                }
+                (InstructionName::jmp, AddressingMode::Absolute) => {
+                    edges.push((target.unwrap(), EdgeKind::Jump));
+                }
+                (InstructionName::jsr, _) => {
+                    edges.push((target.unwrap(), EdgeKind::Call));
+                    edges.push((next, EdgeKind::Fallthrough));
+                }
+                // Returns are added once the subroutines are known
+                (
+                    InstructionName::jmp
+                    | InstructionName::rts
+                    | InstructionName::rti
+                    | InstructionName::brk,
+                    _,
+                ) => {}
+                _ => edges.push((next, EdgeKind::Fallthrough)),
+            }
+            // Targets in the middle of an instruction have no block, and nothing falls through
+            // the end of the address space
+            for (to, kind) in edges {
+                let wraps = kind == EdgeKind::Fallthrough && next < block.start;
+                if graph.blocks.contains_key(&to) && !wraps {
+                    graph.edges.insert(Edge {
+                        from: block.start,
+                        to,
+                        kind,
+                    });
+                }
+            }
+        }
+
+        let mut entries = disassembly.get_entry_points().to_vec();
+        entries.extend(
+            graph
+                .edges
+                .iter()
+                .filter(|edge| edge.kind == EdgeKind::Call)
+                .map(|edge| edge.to),
+        );
+        for entry in entries {
+            if graph.blocks.contains_key(&entry) && !graph.subroutines.contains_key(&entry) {
+                let subroutine = Subroutine {
+                    entry,
+                    name: graph.get_name(entry),
+                    blocks: graph.get_reachable(entry),
+                };
+                graph.subroutines.insert(entry, subroutine);
+            }
+        }
+
+        let mut returns = Vec::new();
+        for subroutine in graph.subroutines.values() {
+            let return_sites = graph
+                .edges
+                .iter()
+                .filter(|edge| edge.kind == EdgeKind::Call && edge.to == subroutine.entry)
+                .filter_map(|call| graph.get_edge(call.from, EdgeKind::Fallthrough))
+                .collect::<Vec<_>>();
+            for &start in &subroutine.blocks {
+                let &(_, instruction) = graph.blocks[&start].instructions.last().unwrap();
+                if instruction.name != InstructionName::rts {
+                    continue;
+                }
+                for &to in &return_sites {
+                    returns.push(Edge {
+                        from: start,
+                        to,
+                        kind: EdgeKind::Return,
+                    });
+                }
+            }
+        }
+        graph.edges.extend(returns);
+        graph
+    }
+
+    /// By start address
+    pub fn get_blocks(&self) -> &BTreeMap<u16, BasicBlock> {
+        &self.blocks
+    }
+
+    pub fn get_edges(&self) -> impl Iterator<Item = &Edge> {
+        self.edges.iter()
+    }
+
+    /// By entry address
+    pub fn get_subroutines(&self) -> &BTreeMap<u16, Subroutine> {
+        &self.subroutines
+    }
+
+    /// The label of `address`, or the address in hexadecimal
+    pub fn get_name(&self, address: u16) -> String {
+        match self.labels.get(&address) {
+            Some(label) => label.clone(),
+            None => format!("${address:04x}"),
+        }
+    }
+
+    /// Writes the whole program as a Graphviz digraph, with a cluster per subroutine. Blocks
+    /// shared by several subroutines are drawn in the first one.
+    pub fn write_dot<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "digraph program {{")?;
+        writeln!(writer, "    node [shape=box, fontname=\"monospace\"];")?;
+        let mut drawn = BTreeSet::new();
+        for subroutine in self.subroutines.values() {
+            writeln!(
+                writer,
+                "    subgraph \"cluster_{:04x}\" {{",
+                subroutine.entry
+            )?;
+            writeln!(writer, "        label=\"{}\";", escape(&subroutine.name))?;
+            for &start in &subroutine.blocks {
+                if drawn.insert(start) {
+                    self.write_block(&mut writer, "        ", &self.blocks[&start])?;
+                }
+            }
+            writeln!(writer, "    }}")?;
+        }
+        for block in self.blocks.values() {
+            if !drawn.contains(&block.start) {
+                self.write_block(&mut writer, "    ", block)?;
+            }
+        }
+        for edge in &self.edges {
+            write_edge(
+                &mut writer,
+                &get_node_id(edge.from),
+                &get_node_id(edge.to),
+                edge.kind,
+            )?;
+        }
+        writeln!(writer, "}}")
+    }
+
+    /// Writes the subroutine at `entry` as a Graphviz digraph. Called subroutines and the return
+    /// are drawn as ellipses. Does nothing if there is no subroutine at `entry`.
+    pub fn write_subroutine_dot<W: Write>(&self, entry: u16, mut writer: W) -> io::Result<()> {
+        let Some(subroutine) = self.subroutines.get(&entry) else {
+            return Ok(());
+        };
+        writeln!(writer, "digraph \"{}\" {{", escape(&subroutine.name))?;
+        writeln!(writer, "    node [shape=box, fontname=\"monospace\"];")?;
+        for &start in &subroutine.blocks {
+            self.write_block(&mut writer, "    ", &self.blocks[&start])?;
+        }
+        let mut callees = BTreeSet::new();
+        let mut returns = false;
+        let mut drawn = BTreeSet::new();
+        for edge in &self.edges {
+            if !subroutine.blocks.contains(&edge.from) {
+                continue;
+            }
+            let to = match edge.kind {
+                EdgeKind::Return => {
+                    returns = true;
+                    "return".to_string()
+                }
+                EdgeKind::Call => {
+                    callees.insert(edge.to);
+                    format!("call_{:04x}", edge.to)
+                }
+                _ if subroutine.blocks.contains(&edge.to) => get_node_id(edge.to),
+                _ => continue,
+            };
+            // Every return site of the subroutine becomes the same node
+            if drawn.insert((edge.from, to.clone())) {
+                write_edge(&mut writer, &get_node_id(edge.from), &to, edge.kind)?;
+            }
+        }
+        for callee in callees {
+            writeln!(
+                writer,
+                "    \"call_{callee:04x}\" [shape=ellipse, label=\"{}\"];",
+                escape(&self.get_name(callee))
+            )?;
+        }
+        if returns {
+            writeln!(writer, "    \"return\" [shape=ellipse];")?;
+        }
+        writeln!(writer, "}}")
+    }
+
+    fn write_block<W: Write>(
+        &self,
+        writer: &mut W,
+        indent: &str,
+        block: &BasicBlock,
+    ) -> io::Result<()> {
+        // `\l` ends a left aligned line
+        let mut label = String::new();
+        if let Some(name) = self.labels.get(&block.start) {
+            label.push_str(&format!("{}:\\l", escape(name)));
+        }
+        for (address, instruction) in &block.instructions {
+            let text = match get_target(*address, instruction) {
+                Some(target) => format!("{} {}", instruction.name, self.get_name(target)),
+                None => instruction.to_string(),
+            };
+            label.push_str(&format!("{address:04x}  {}\\l", escape(&text)));
+        }
+        writeln!(
+            writer,
+            "{indent}\"{}\" [label=\"{label}\"];",
+            get_node_id(block.start)
+        )
+    }
+
+    fn get_edge(&self, from: u16, kind: EdgeKind) -> Option<u16> {
+        self.edges
+            .iter()
+            .find(|edge| edge.from == from && edge.kind == kind)
+            .map(|edge| edge.to)
+    }
+
+    // The blocks reachable from `entry` through fallthroughs, branches and jumps
+    fn get_reachable(&self, entry: u16) -> BTreeSet<u16> {
+        let mut reachable = BTreeSet::new();
+        let mut pending = vec![entry];
+        while let Some(start) = pending.pop() {
+            if !reachable.insert(start) {
+                continue;
+            }
+            pending.extend(
+                self.edges
+                    .range(Edge::first_from(start)..)
+                    .take_while(|edge| edge.from == start)
+                    .filter(|edge| {
+                        matches!(
+                            edge.kind,
+                            EdgeKind::Fallthrough | EdgeKind::Branch | EdgeKind::Jump
+                        )
+                    })
+                    .map(|edge| edge.to),
+            );
+        }
+        reachable
+    }
+}
+
+impl Edge {
+    // The smallest edge from `from`, to look edges up by their source
+    fn first_from(from: u16) -> Self {
+        Self {
+            from,
+            to: 0,
+            kind: EdgeKind::Fallthrough,
+        }
+    }
+}
+
+// Where a branch, an absolute jump or a call goes
+fn get_target(address: u16, instruction: &Instruction) -> Option<u16> {
+    match (instruction.name, instruction.addressing_mode) {
+        (_, AddressingMode::Relative) => instruction.get_branch_target(address),
+        (InstructionName::jmp, AddressingMode::Absolute) | (InstructionName::jsr, _) => {
+            Some(instruction.operand)
+        }
+        _ => None,
+    }
+}
+
+// Whether the instruction can go anywhere but the next one
+fn ends_block(instruction: &Instruction) -> bool {
+    instruction.addressing_mode == AddressingMode::Relative
+        || matches!(
+            instruction.name,
+            InstructionName::jmp
+                | InstructionName::jsr
+                | InstructionName::rts
+                | InstructionName::rti
+                | InstructionName::brk
+        )
+}
+
+fn get_node_id(start: u16) -> String {
+    format!("block_{start:04x}")
+}
+
+fn write_edge<W: Write>(writer: &mut W, from: &str, to: &str, kind: EdgeKind) -> io::Result<()> {
+    match kind.get_attributes() {
+        "" => writeln!(writer, "    \"{from}\" -> \"{to}\";"),
+        attributes => writeln!(writer, "    \"{from}\" -> \"{to}\" [{attributes}];"),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::mem::Memory;
+
+    const BRANCH_SOURCE: &str = "
+.org $8000
+reset:
+    lda $10
+    beq skip
+    inx
+skip:
+    jmp skip
+.org $fffa
+    .word reset, reset, reset
+";
+
+    fn new_graph() -> ControlFlowGraph {
+        let memory = Memory::new_from_bytes(assemble(BRANCH_SOURCE).unwrap());
+        ControlFlowGraph::new(&Disassembly::new(&memory, &[]))
+    }
+
+    #[test]
+    fn a_branch_splits_the_blocks_at_its_target_and_after_it() {
+        let graph = new_graph();
+        let blocks = graph
+            .get_blocks()
+            .values()
+            .map(|block| (block.start, block.get_end()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            blocks,
+            [(0x8000, 0x8004), (0x8004, 0x8005), (0x8005, 0x8008)]
+        );
+        let edges = graph
+            .get_edges()
+            .map(|edge| (edge.from, edge.to, edge.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            edges,
+            [
+                (0x8000, 0x8004, EdgeKind::Fallthrough),
+                (0x8000, 0x8005, EdgeKind::Branch),
+                (0x8004, 0x8005, EdgeKind::Fallthrough),
+                (0x8005, 0x8005, EdgeKind::Jump),
+            ]
+        );
+    }
+
+    #[test]
+    fn dot_output_has_the_taken_and_fall_through_edges() {
+        let mut dot = Vec::new();
+        new_graph().write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph program {\n"));
+        assert!(dot.contains("    subgraph \"cluster_8000\" {\n        label=\"reset\";\n"));
+        for block in ["block_8000", "block_8004", "block_8005"] {
+            assert!(dot.contains(&format!("        \"{block}\" [")), "{block}");
+        }
+        assert!(dot.contains("    \"block_8000\" -> \"block_8004\";\n"));
+        assert!(dot.contains(
+            "    \"block_8000\" -> \"block_8005\" [color=darkgreen, label=\"taken\"];\n"
+        ));
+        assert!(dot.contains("    \"block_8004\" -> \"block_8005\";\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use bitflags::bitflags;
+
+use crate::assembler::ListingLine;
+use crate::decoder::decode_from;
+use crate::emulator::StepResult;
+use crate::instruction::AddressingMode;
+use crate::mem::MEM_SIZE;
+use crate::observer::EmulatorObserver;
+use crate::readwritable::ReadWritable;
+use crate::regs::Regs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ByteUsage(u8);
+
+bitflags! {
+    impl ByteUsage: u8 {
+        /// Fetched as the op code of an instruction
+        const OPCODE    = 0b0001;
+        /// Fetched as the operand of an instruction
+        const OPERAND   = 0b0010;
+        const READ      = 0b0100;
+        const WRITTEN   = 0b1000;
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Records which bytes were executed, read and written, and which way every branch went. Attach
+/// it with `Emulator::add_observer`, through an `Rc<RefCell<Coverage>>` to read it back.
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    usage: Vec<ByteUsage>,
+    executions: HashMap<u16, u64>,
+    branches: HashMap<u16, BranchCoverage>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            usage: vec![ByteUsage::empty(); MEM_SIZE],
+            executions: HashMap::new(),
+            branches: HashMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.usage.fill(ByteUsage::empty());
+        self.executions.clear();
+        self.branches.clear();
+    }
+
+    pub fn get_usage(&self, address: u16) -> ByteUsage {
+        self.usage[address as usize]
+    }
+
+    /// How many times the instruction at `address` executed
+    pub fn get_executions(&self, address: u16) -> u64 {
+        self.executions.get(&address).copied().unwrap_or(0)
+    }
+
+    /// The branches that executed at least once, by address
+    pub fn get_branches(&self) -> &HashMap<u16, BranchCoverage> {
+        &self.branches
+    }
+
+    /// How many bytes were used in any of the ways in `usage`
+    pub fn count(&self, usage: ByteUsage) -> usize {
+        self.usage
+            .iter()
+            .filter(|byte| byte.intersects(usage))
+            .count()
+    }
+
+    /// Writes every used byte in address order: instructions disassembled with their execution
+    /// counts and branch directions, data bytes with how they were accessed
+    pub fn write_listing<W: Write>(&self, bus: &dyn ReadWritable, mut writer: W) -> io::Result<()> {
+        let directions = self
+            .branches
+            .values()
+            .map(|branch| (branch.taken > 0) as usize + (branch.not_taken > 0) as usize)
+            .sum::<usize>();
+        writeln!(
+            writer,
+            "Executed {} bytes, read {}, written {}",
+            self.count(ByteUsage::OPCODE | ByteUsage::OPERAND),
+            self.count(ByteUsage::READ),
+            self.count(ByteUsage::WRITTEN)
+        )?;
+        writeln!(
+            writer,
+            "Covered {directions} of {} branch directions",
+            self.branches.len() * 2
+        )?;
+
+        let mut address = 0;
+        let mut in_gap = true;
+        while address < MEM_SIZE {
+            let usage = self.usage[address];
+            if usage.is_empty() {
+                in_gap = true;
+                address += 1;
+                continue;
+            }
+            if in_gap {
+                writeln!(writer)?;
+                in_gap = false;
+            }
+            let addr = address as u16;
+            if usage.contains(ByteUsage::OPCODE) {
+                let instruction = decode_from(bus, addr).ok().map(|(ins, _)| ins);
+                let size = instruction.map_or(1, |ins| ins.size());
+                let bytes = (0..size)
+                    .map(|offset| format!("{:02x}", bus.peek(addr.wrapping_add(offset))))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let text = match instruction {
+                    Some(ins) => ins.disassemble(addr),
+                    None => format!(".byte ${:02x}", bus.peek(addr)),
+                };
+                let branch = match self.branches.get(&addr) {
+                    Some(branch) => {
+                        format!("taken {}, not taken {}", branch.taken, branch.not_taken)
+                    }
+                    None => String::new(),
+                };
+                let line = format!(
+                    "{:>8}  {addr:04x}  {bytes:<8}  {text:<16}  {:<3}  {branch}",
+                    self.get_executions(addr),
+                    get_access(usage)
+                );
+                writeln!(writer, "{}", line.trim_end())?;
+                address += size as usize;
+            } else {
+                let byte = bus.peek(addr);
+                let text = format!(".byte ${byte:02x}");
+                let line = format!(
+                    "{:>8}  {addr:04x}  {byte:02x}        {text:<16}  {}",
+                    "-",
+                    get_access(usage)
+                );
+                writeln!(writer, "{}", line.trim_end())?;
+                address += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes an LCOV tracefile for the source that `listing` came from, with line coverage for
+    /// every instruction and branch coverage for every branch
+    pub fn write_lcov<W: Write>(
+        &self,
+        source_path: &str,
+        listing: &[ListingLine],
+        bus: &dyn ReadWritable,
+        mut writer: W,
+    ) -> io::Result<()> {
+        writeln!(writer, "TN:")?;
+        writeln!(writer, "SF:{source_path}")?;
+        let (mut lines_found, mut lines_hit) = (0, 0);
+        let (mut branches_found, mut branches_hit) = (0, 0);
+        for entry in listing.iter().filter(|entry| entry.is_instruction) {
+            let executions = self.get_executions(entry.address);
+            lines_found += 1;
+            if executions > 0 {
+                lines_hit += 1;
+            }
+            writeln!(writer, "DA:{},{executions}", entry.line)?;
+
+            let is_branch = decode_from(bus, entry.address)
+                .is_ok_and(|(ins, _)| ins.addressing_mode == AddressingMode::Relative);
+            if !is_branch {
+                continue;
+            }
+            let branch = self.branches.get(&entry.address);
+            for (index, count) in [branch.map(|b| b.taken), branch.map(|b| b.not_taken)]
+                .into_iter()
+                .enumerate()
+            {
+                branches_found += 1;
+                let count = match count {
+                    Some(count) => {
+                        if count > 0 {
+                            branches_hit += 1;
+                        }
+                        count.to_string()
+                    }
+                    // LCOV's way of saying the branch itself never executed
+                    None => "-".to_string(),
+                };
+                writeln!(writer, "BRDA:{},0,{index},{count}", entry.line)?;
+            }
+        }
+        writeln!(writer, "BRF:{branches_found}")?;
+        writeln!(writer, "BRH:{branches_hit}")?;
+        writeln!(writer, "LF:{lines_found}")?;
+        writeln!(writer, "LH:{lines_hit}")?;
+        writeln!(writer, "end_of_record")
+    }
+}
+
+impl EmulatorObserver for Coverage {
+    fn on_step(&mut self, result: &StepResult, _regs: &Regs) {
+        let address = result.address;
+        *self.executions.entry(address).or_default() += 1;
+        self.usage[address as usize] |= ByteUsage::OPCODE;
+        let size = result.instruction.map_or(1, |ins| ins.size());
+        for offset in 1..size {
+            self.usage[address.wrapping_add(offset) as usize] |= ByteUsage::OPERAND;
+        }
+        if let Some(taken) = result.branch_taken {
+            let branch = self.branches.entry(address).or_default();
+            if taken {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+
+    fn on_read(&mut self, address: u16, _value: u8) {
+        self.usage[address as usize] |= ByteUsage::READ;
+    }
+
+    fn on_write(&mut self, address: u16, _old: u8, _new: u8) {
+        self.usage[address as usize] |= ByteUsage::WRITTEN;
+    }
+}
+
+// The data accesses of a byte, e.g. `R W`, padded to the same width
+fn get_access(usage: ByteUsage) -> String {
+    let read = if usage.contains(ByteUsage::READ) {
+        "R"
+    } else {
+        ""
+    };
+    let written = if usage.contains(ByteUsage::WRITTEN) {
+        "W"
+    } else {
+        ""
+    };
+    format!("{read:<2}{written}")
+}
@@ -1,16 +1,79 @@
-use crate::instruction::{AddressingMode, Instruction, InstructionRegistry};
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
 
+use crate::instruction::{Instruction, InstructionRegistry};
+use crate::readwritable::ReadWritable;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownOpCode(u8),
+    /// The instruction at this address goes past the end of the bytes
+    Truncated(u16),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnknownOpCode(op_code) => write!(f, "unknown op code {op_code:#04x}"),
+            DecodeError::Truncated(address) => {
+                write!(f, "the instruction at {address:#06x} is truncated")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// Building a registry fills in its op code table, which only needs to happen once
+pub(crate) fn get_registry() -> &'static InstructionRegistry {
+    static REGISTRY: OnceLock<InstructionRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(InstructionRegistry::new)
+}
+
+/// Decodes the instruction at the start of `bytes`, which is at `addr`, and returns it along
+/// with its size in bytes
+pub fn decode(bytes: &[u8], addr: u16) -> Result<(Instruction, usize), DecodeError> {
+    let &op_code = bytes.first().ok_or(DecodeError::Truncated(addr))?;
+    let mut instruction = get_registry()
+        .get_instruction_by_op_code(op_code, 0)
+        .ok_or(DecodeError::UnknownOpCode(op_code))?;
+    let size = instruction.size() as usize;
+    let operand = bytes.get(1..size).ok_or(DecodeError::Truncated(addr))?;
+    // Operands are little endian
+    instruction.operand = operand
+        .iter()
+        .rev()
+        .fold(0, |operand, &byte| (operand << 8) | byte as u16);
+    Ok((instruction, size))
+}
+
+/// Like `decode`, for the instruction at `addr` on `bus`. Only the bytes of the instruction are
+/// peeked at, and operands wrap around at the end of the address space.
+pub fn decode_from(bus: &dyn ReadWritable, addr: u16) -> Result<(Instruction, usize), DecodeError> {
+    let op_code = bus.peek(addr);
+    let size = get_registry()
+        .get_opcode_info(op_code)
+        .map_or(1, |info| info.length as usize);
+    let mut bytes = [op_code, 0, 0];
+    for (offset, byte) in bytes.iter_mut().enumerate().take(size).skip(1) {
+        *byte = bus.peek(addr.wrapping_add(offset as u16));
+    }
+    decode(&bytes[..size], addr)
+}
+
+/// Decodes instructions one after the other from the bytes returned by `next_byte`. Kept for
+/// callers of the closure based API; `decode` and `decode_from` need no closure.
 pub struct Decoder {
-    registry: InstructionRegistry,
     next_byte: Box<dyn FnMut() -> u8>,
 }
 
 impl Decoder {
     pub fn new(next_byte: Box<dyn FnMut() -> u8>) -> Self {
-        Self {
-            registry: InstructionRegistry::new(),
-            next_byte,
-        }
+        Self { next_byte }
+    }
+
+    pub fn get_registry(&self) -> &InstructionRegistry {
+        get_registry()
     }
 
     pub fn next_word(&mut self) -> u16 {
@@ -20,37 +83,75 @@ impl Decoder {
         (higher << 8) | lower
     }
 
-    pub fn decode_next(&mut self) -> Instruction {
+    pub fn decode_next(&mut self) -> Result<Instruction, DecodeError> {
         let byte = (self.next_byte)();
-        let mut instruction = self
-            .registry
+        let mut instruction = get_registry()
             .get_instruction_by_op_code(byte, 0)
-            .expect(format!("Cannot read op code {:#04x}", byte).as_str());
-
-        match instruction.addressing_mode {
-            // No operand
-            AddressingMode::Implicit | AddressingMode::Accumulator => {}
-
-            // Byte operand
-            AddressingMode::Immediate
-            | AddressingMode::ZeroPage
-            | AddressingMode::ZeroPageX
-            | AddressingMode::ZeroPageY
-            | AddressingMode::Relative
-            | AddressingMode::IndirectX
-            | AddressingMode::IndirectY => {
-                instruction.operand = (self.next_byte)() as u16;
-            }
+            .ok_or(DecodeError::UnknownOpCode(byte))?;
+        instruction.operand = match instruction.size() {
+            2 => (self.next_byte)() as u16,
+            3 => self.next_word(),
+            _ => 0,
+        };
+        Ok(instruction)
+    }
+}
 
-            // Word operand
-            AddressingMode::Absolute
-            | AddressingMode::AbsoluteX
-            | AddressingMode::AbsoluteY
-            | AddressingMode::Indirect => {
-                instruction.operand = self.next_word();
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
 
-        instruction
+    use super::*;
+    use crate::instruction::{AddressingMode, InstructionName};
+    use crate::mem::Memory;
+
+    #[test]
+    fn decode_returns_the_instruction_and_its_size() {
+        let (instruction, size) = decode(&[0xbd, 0x34, 0x12, 0xea], 0x8000).unwrap();
+        assert_eq!(instruction.name, InstructionName::lda);
+        assert_eq!(instruction.addressing_mode, AddressingMode::AbsoluteX);
+        assert_eq!(instruction.operand, 0x1234);
+        assert_eq!(size, 3);
+    }
+
+    #[test]
+    fn decode_reports_unknown_op_codes_and_truncated_operands() {
+        assert_eq!(
+            decode(&[0x02], 0x8000),
+            Err(DecodeError::UnknownOpCode(0x02))
+        );
+        assert_eq!(
+            decode(&[0xad, 0x34], 0x8000),
+            Err(DecodeError::Truncated(0x8000))
+        );
+        assert_eq!(decode(&[], 0x8000), Err(DecodeError::Truncated(0x8000)));
+    }
+
+    #[test]
+    fn decode_from_wraps_around_the_address_space() {
+        let mut memory = Memory::new();
+        memory.write(0xffff, 0xad);
+        memory.write(0x0000, 0x34);
+        memory.write(0x0001, 0x12);
+        let (instruction, size) = decode_from(&memory, 0xffff).unwrap();
+        assert_eq!(instruction.operand, 0x1234);
+        assert_eq!(size, 3);
+    }
+
+    #[test]
+    fn decoder_fetches_the_operand_bytes() {
+        let bytes = [0xad, 0x34, 0x12, 0x02];
+        let next = Rc::new(Cell::new(0));
+        let mut decoder = Decoder::new(Box::new({
+            let next = next.clone();
+            move || {
+                next.set(next.get() + 1);
+                bytes[next.get() - 1]
+            }
+        }));
+        assert_eq!(decoder.decode_next().unwrap().operand, 0x1234);
+        assert_eq!(next.get(), 3);
+        assert_eq!(decoder.decode_next(), Err(DecodeError::UnknownOpCode(0x02)));
     }
 }
@@ -0,0 +1,449 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead};
+
+use crate::emulator::{Emulator, EmulatorError};
+use crate::readwritable::ReadWritable;
+use crate::regs::CpuFlags;
+use crate::trace::{TraceFormat, TraceLine};
+
+#[derive(Debug)]
+pub enum DiffError {
+    Io(io::Error),
+    /// A line of the reference trace could not be parsed
+    Parse {
+        line: usize,
+        reason: String,
+    },
+    /// The emulator faulted while executing the instruction of a reference line
+    Emulator {
+        line: usize,
+        error: EmulatorError,
+    },
+}
+
+impl Display for DiffError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiffError::Io(err) => write!(f, "{err}"),
+            DiffError::Parse { line, reason } => {
+                write!(f, "cannot parse reference line {line}: {reason}")
+            }
+            DiffError::Emulator { line, error } => {
+                write!(f, "{error} while executing reference line {line}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DiffError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiffError::Io(err) => Some(err),
+            DiffError::Emulator { error, .. } => Some(error),
+            DiffError::Parse { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for DiffError {
+    fn from(err: io::Error) -> Self {
+        DiffError::Io(err)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TraceField {
+    Pc,
+    A,
+    X,
+    Y,
+    P,
+    Sp,
+    Cycles,
+}
+
+impl TraceField {
+    // The label of the field in the nestest layout
+    fn get_label(&self) -> &'static str {
+        match self {
+            TraceField::Pc => "",
+            TraceField::A => "A:",
+            TraceField::X => "X:",
+            TraceField::Y => "Y:",
+            TraceField::P => "P:",
+            TraceField::Sp => "SP:",
+            TraceField::Cycles => "CYC:",
+        }
+    }
+}
+
+impl Display for TraceField {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceField::Pc => write!(f, "PC"),
+            TraceField::A => write!(f, "A"),
+            TraceField::X => write!(f, "X"),
+            TraceField::Y => write!(f, "Y"),
+            TraceField::P => write!(f, "P"),
+            TraceField::Sp => write!(f, "SP"),
+            TraceField::Cycles => write!(f, "CYC"),
+        }
+    }
+}
+
+/// The state a reference emulator logged before an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReferenceLine {
+    pub address: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub cycles: u64,
+    /// The line as it appears in the reference trace
+    pub text: String,
+}
+
+impl ReferenceLine {
+    /// Parses a line in either layout `Tracer` writes. Nestest lines may carry extra columns,
+    /// such as the PPU position of the original nestest.log.
+    pub fn parse(line: &str, format: TraceFormat) -> Result<Self, String> {
+        match format {
+            TraceFormat::Nestest => Self::parse_nestest(line),
+            TraceFormat::Json => Self::parse_json(line),
+        }
+    }
+
+    fn parse_nestest(line: &str) -> Result<Self, String> {
+        let address = line
+            .get(..4)
+            .and_then(|address| u16::from_str_radix(address, 16).ok())
+            .ok_or("the line does not start with an address")?;
+        // The disassembly comes before the registers and may contain anything
+        let regs_start = line.find("A:").ok_or("missing field A")?;
+        let regs = &line[regs_start..];
+        let hex = |label: &str| -> Result<u8, String> {
+            let value = find_field(regs, label).ok_or(format!("missing field {label}"))?;
+            let digits = value.get(..2).unwrap_or(value);
+            u8::from_str_radix(digits, 16).map_err(|_| format!("invalid value for {label}"))
+        };
+        let cycles = find_field(regs, "CYC:").ok_or("missing field CYC")?;
+        let digits = cycles.trim_start();
+        let end = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+        Ok(Self {
+            address,
+            a: hex("A:")?,
+            x: hex("X:")?,
+            y: hex("Y:")?,
+            p: hex("P:")?,
+            sp: hex("SP:")?,
+            cycles: digits[..end]
+                .parse()
+                .map_err(|_| "invalid value for CYC".to_string())?,
+            text: line.to_string(),
+        })
+    }
+
+    fn parse_json(line: &str) -> Result<Self, String> {
+        let number = |key: &str| -> Result<u64, String> {
+            let value = line
+                .find(&format!("\"{key}\":"))
+                .map(|index| &line[index + key.len() + 3..])
+                .ok_or(format!("missing field {key}"))?;
+            let end = value
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(value.len());
+            value[..end]
+                .parse()
+                .map_err(|_| format!("invalid value for {key}"))
+        };
+        let byte = |key: &str| -> Result<u8, String> {
+            u8::try_from(number(key)?).map_err(|_| format!("{key} does not fit in a byte"))
+        };
+        Ok(Self {
+            address: u16::try_from(number("pc")?).map_err(|_| "pc does not fit in a word")?,
+            a: byte("a")?,
+            x: byte("x")?,
+            y: byte("y")?,
+            p: byte("p")?,
+            sp: byte("sp")?,
+            cycles: number("cyc")?,
+            text: line.to_string(),
+        })
+    }
+
+    /// The fields of `line` that differ from this one. The break and unused bits of P are
+    /// ignored since they do not exist in the status register.
+    pub fn compare(&self, line: &TraceLine) -> Vec<TraceField> {
+        let ignored = (CpuFlags::BREAK | CpuFlags::UNUSED).bits();
+        [
+            (TraceField::Pc, self.address == line.address),
+            (TraceField::A, self.a == line.regs.a),
+            (TraceField::X, self.x == line.regs.x),
+            (TraceField::Y, self.y == line.regs.y),
+            (
+                TraceField::P,
+                self.p & !ignored == line.get_status() & !ignored,
+            ),
+            (TraceField::Sp, self.sp == line.regs.sp),
+            (TraceField::Cycles, self.cycles == line.cycles),
+        ]
+        .into_iter()
+        .filter(|&(_, equal)| !equal)
+        .map(|(field, _)| field)
+        .collect()
+    }
+}
+
+// The text after a label that is not part of a longer label, e.g. `P:` but not the end of `SP:`
+fn find_field<'a>(text: &'a str, label: &str) -> Option<&'a str> {
+    let mut start = 0;
+    while let Some(index) = text[start..].find(label) {
+        let index = start + index;
+        let preceded_by_letter = text[..index]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_ascii_alphabetic());
+        if !preceded_by_letter {
+            return Some(&text[index + label.len()..]);
+        }
+        start = index + label.len();
+    }
+    None
+}
+
+/// Where the emulator first disagreed with the reference trace
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// The line number in the reference trace, starting at 1
+    pub line: usize,
+    pub expected: ReferenceLine,
+    pub actual: TraceLine,
+    pub fields: Vec<TraceField>,
+    /// The matching lines just before the divergence
+    pub before: Vec<TraceLine>,
+    /// The reference lines just after the divergence
+    pub expected_after: Vec<ReferenceLine>,
+    /// What the emulator executed after the divergence
+    pub actual_after: Vec<TraceLine>,
+}
+
+impl Divergence {
+    // Carets under the differing fields of the nestest layout of `actual`
+    fn get_markers(&self) -> String {
+        let line = self.actual.to_nestest();
+        let regs_start = line.find("A:").unwrap_or(line.len());
+        let mut markers = vec![' '; line.len()];
+        for field in &self.fields {
+            let range = match field {
+                TraceField::Pc => 0..4,
+                _ => {
+                    let label = field.get_label();
+                    let Some(value) = find_field(&line[regs_start..], label) else {
+                        continue;
+                    };
+                    let start = line.len() - value.len();
+                    let end = value.find(' ').map_or(line.len(), |end| start + end);
+                    start..end
+                }
+            };
+            markers[range].fill('^');
+        }
+        markers
+            .into_iter()
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let fields = self
+            .fields
+            .iter()
+            .map(|field| field.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(
+            f,
+            "First divergence at reference line {} in {fields}",
+            self.line
+        )?;
+        for line in &self.before {
+            writeln!(f, "  {}", line.to_nestest())?;
+        }
+        writeln!(f, "- {}", self.expected.text)?;
+        writeln!(f, "+ {}", self.actual.to_nestest())?;
+        writeln!(f, "  {}", self.get_markers())?;
+        if !self.expected_after.is_empty() || !self.actual_after.is_empty() {
+            writeln!(f, "Afterwards:")?;
+        }
+        for line in &self.expected_after {
+            writeln!(f, "- {}", line.text)?;
+        }
+        for line in &self.actual_after {
+            writeln!(f, "+ {}", line.to_nestest())?;
+        }
+        Ok(())
+    }
+}
+
+/// Steps `emulator` from its current state once per line of `reference` and returns the first
+/// line whose PC, registers, flags or cycle count differ, along with up to `context` lines
+/// around it. Returns `None` if every line matches.
+///
+/// Cycle counts are compared relative to the first line, since the reference may have counted
+/// the reset sequence; the cycles of the returned trace lines are shifted to match.
+pub fn diff_trace<R: BufRead, B: ReadWritable>(
+    emulator: &mut Emulator<B>,
+    reference: R,
+    format: TraceFormat,
+    context: usize,
+) -> Result<Option<Divergence>, DiffError> {
+    let mut lines = reference
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|(index, line)| {
+            let line = line?;
+            ReferenceLine::parse(&line, format)
+                .map(|parsed| (index + 1, parsed))
+                .map_err(|reason| DiffError::Parse {
+                    line: index + 1,
+                    reason,
+                })
+        });
+    let mut cycle_offset = None;
+    let mut before = VecDeque::with_capacity(context);
+    while let Some(expected) = lines.next() {
+        let (line_number, expected) = expected?;
+        let mut actual = emulator.get_trace_line();
+        let offset = *cycle_offset.get_or_insert(expected.cycles.wrapping_sub(actual.cycles));
+        actual.cycles = actual.cycles.wrapping_add(offset);
+
+        let fields = expected.compare(&actual);
+        if !fields.is_empty() {
+            let expected_after = lines
+                .by_ref()
+                .take(context)
+                .map(|line| line.map(|(_, line)| line))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut actual_after = Vec::with_capacity(context);
+            // Whatever happens next is only shown, so a fault ends it early
+            while actual_after.len() < context && emulator.try_step().is_ok() {
+                let mut line = emulator.get_trace_line();
+                line.cycles = line.cycles.wrapping_add(offset);
+                actual_after.push(line);
+            }
+            return Ok(Some(Divergence {
+                line: line_number,
+                expected,
+                actual,
+                fields,
+                before: before.into(),
+                expected_after,
+                actual_after,
+            }));
+        }
+
+        emulator.try_step().map_err(|error| DiffError::Emulator {
+            line: line_number,
+            error,
+        })?;
+        if context > 0 {
+            if before.len() == context {
+                before.pop_front();
+            }
+            before.push_back(actual);
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::mem::Memory;
+
+    fn load(source: &str) -> Emulator<Memory> {
+        let image = assemble(&format!(".org $0200\n{source}\nbrk\n")).unwrap();
+        let mut emulator = Emulator::new(Memory::new_from_bytes(image));
+        emulator.get_regs_mut().pc = 0x0200;
+        emulator
+    }
+
+    // The trace of the first `count` instructions of `source`, as a reference emulator logs it
+    fn trace(source: &str, count: usize) -> String {
+        let mut emulator = load(source);
+        let mut trace = String::new();
+        for _ in 0..count {
+            trace += &emulator.get_trace_line().to_nestest();
+            trace += "\n";
+            emulator.step();
+        }
+        trace
+    }
+
+    const SOURCE: &str = "lda #$01\nldx #$02\nldy #$03\ninx\niny\nnop";
+
+    #[test]
+    fn matching_traces_have_no_divergence() {
+        let reference = trace(SOURCE, 6);
+        let divergence = diff_trace(
+            &mut load(SOURCE),
+            reference.as_bytes(),
+            TraceFormat::Nestest,
+            2,
+        );
+        assert!(divergence.unwrap().is_none());
+    }
+
+    #[test]
+    fn the_first_divergence_is_reported_with_its_context() {
+        let reference = trace(SOURCE, 6);
+        let mut emulator = load("lda #$01\nldx #$07\nldy #$03\ninx\niny\nnop");
+        let divergence = diff_trace(&mut emulator, reference.as_bytes(), TraceFormat::Nestest, 2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.line, 3);
+        assert_eq!(divergence.fields, [TraceField::X]);
+        assert_eq!(divergence.expected.x, 0x02);
+        assert_eq!(divergence.actual.regs.x, 0x07);
+        let before = divergence
+            .before
+            .iter()
+            .map(|line| line.address)
+            .collect::<Vec<_>>();
+        assert_eq!(before, [0x0200, 0x0202]);
+        let expected_after = divergence
+            .expected_after
+            .iter()
+            .map(|line| line.address)
+            .collect::<Vec<_>>();
+        assert_eq!(expected_after, [0x0206, 0x0207]);
+        assert_eq!(divergence.actual_after.len(), 2);
+
+        let report = divergence.to_string();
+        assert!(report.starts_with("First divergence at reference line 3 in X\n"));
+        let reference_line = reference.lines().nth(2).unwrap();
+        assert!(report.contains(&format!("- {reference_line}\n")));
+    }
+
+    #[test]
+    fn unparseable_lines_are_reported_with_their_number() {
+        let reference = format!("{}garbage\n", trace(SOURCE, 1));
+        let result = diff_trace(
+            &mut load(SOURCE),
+            reference.as_bytes(),
+            TraceFormat::Nestest,
+            0,
+        );
+        assert!(matches!(result, Err(DiffError::Parse { line: 2, .. })));
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use crate::decoder::{decode, decode_from, get_registry};
+use crate::emulator::{IRQ_VEC_LOW_ADDR, NMI_VEC_LOW_ADDR, RESET_VEC_LOW_ADDR};
+use crate::instruction::{AddressingMode, Instruction, InstructionName};
+use crate::mem::MEM_SIZE;
+use crate::readwritable::ReadWritable;
+
+/// An instruction, or a byte that does not decode to one, and where it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisassembledLine {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// `None` for unknown op codes and instructions cut off by the end of the bytes
+    pub instruction: Option<Instruction>,
+}
+
+impl DisassembledLine {
+    /// The instruction with branch targets as absolute addresses, or a `.byte` directive
+    pub fn disassemble(&self) -> String {
+        match self.instruction {
+            Some(ins) => ins.disassemble(self.address),
+            None => format!(".byte ${:02x}", self.bytes[0]),
+        }
+    }
+}
+
+impl Display for DisassembledLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(
+            f,
+            "{:04x}  {bytes:<8}  {}",
+            self.address,
+            self.disassemble()
+        )
+    }
+}
+
+enum Source<'a> {
+    Bus(&'a dyn ReadWritable),
+    Bytes(&'a [u8]),
+}
+
+/// Disassembles bytes one instruction after the other, without running them. Bytes that do not
+/// decode to an instruction are yielded one at a time with no instruction.
+pub struct Disassembler<'a> {
+    source: Source<'a>,
+    start: u16,
+    offset: usize,
+    len: usize,
+}
+
+impl<'a> Disassembler<'a> {
+    /// Disassembles the instructions that start in `range` of `bus`. The last one may end past
+    /// the range.
+    pub fn new(bus: &'a dyn ReadWritable, range: RangeInclusive<u16>) -> Self {
+        Self {
+            source: Source::Bus(bus),
+            start: *range.start(),
+            offset: 0,
+            len: range.len(),
+        }
+    }
+
+    /// Disassembles `bytes` as if they were loaded at `start`, e.g. a ROM image
+    pub fn new_from_bytes(bytes: &'a [u8], start: u16) -> Self {
+        Self {
+            source: Source::Bytes(bytes),
+            start,
+            offset: 0,
+            len: bytes.len(),
+        }
+    }
+}
+
+impl Iterator for Disassembler<'_> {
+    type Item = DisassembledLine;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.len {
+            return None;
+        }
+        let address = self.start.wrapping_add(self.offset as u16);
+        let (instruction, bytes) = match self.source {
+            Source::Bus(bus) => {
+                let instruction = decode_from(bus, address).ok();
+                let size = instruction.map_or(1, |(_, size)| size);
+                let bytes = (0..size)
+                    .map(|offset| bus.peek(address.wrapping_add(offset as u16)))
+                    .collect::<Vec<_>>();
+                (instruction, bytes)
+            }
+            Source::Bytes(bytes) => {
+                let rest = &bytes[self.offset..];
+                let instruction = decode(rest, address).ok();
+                let size = instruction.map_or(1, |(_, size)| size);
+                (instruction, rest[..size].to_vec())
+            }
+        };
+        self.offset += bytes.len();
+        Some(DisassembledLine {
+            address,
+            bytes,
+            instruction: instruction.map(|(instruction, _)| instruction),
+        })
+    }
+}
+
+/// What following the control flow found a byte to be
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ByteKind {
+    /// Never reached from an entry point
+    Data,
+    OpCode,
+    Operand,
+}
+
+/// The code and data of an image, told apart by following the control flow from the reset,
+/// NMI and IRQ vectors and any other entry points instead of decoding every byte in order.
+/// Every target of a jump, call or branch gets a label.
+#[derive(Debug, Clone)]
+pub struct Disassembly {
+    kinds: Vec<ByteKind>,
+    instructions: BTreeMap<u16, Instruction>,
+    labels: BTreeMap<u16, String>,
+    entry_points: Vec<u16>,
+}
+
+impl Disassembly {
+    pub fn new(bus: &dyn ReadWritable, entry_points: &[u16]) -> Self {
+        let mut disassembly = Self {
+            kinds: vec![ByteKind::Data; MEM_SIZE],
+            instructions: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            entry_points: Vec::new(),
+        };
+        for (name, vector) in VECTORS {
+            let address = read_word(bus, vector);
+            disassembly.add_label(address, name.to_string());
+            disassembly.entry_points.push(address);
+        }
+        for &address in entry_points {
+            disassembly.add_label(address, format!("entry_{address:04x}"));
+            disassembly.entry_points.push(address);
+        }
+        let mut seen = HashSet::new();
+        disassembly
+            .entry_points
+            .retain(|&address| seen.insert(address));
+        let mut pending = disassembly.entry_points.clone();
+        while let Some(address) = pending.pop() {
+            disassembly.follow(bus, address, &mut pending);
+        }
+        disassembly
+    }
+
+    pub fn get_kind(&self, address: u16) -> ByteKind {
+        self.kinds[address as usize]
+    }
+
+    /// The instructions reached, by address
+    pub fn get_instructions(&self) -> &BTreeMap<u16, Instruction> {
+        &self.instructions
+    }
+
+    /// The handlers of the reset, NMI and IRQ vectors, then the other entry points
+    pub fn get_entry_points(&self) -> &[u16] {
+        &self.entry_points
+    }
+
+    /// The labels of the entry points and of the jump, call and branch targets, by address
+    pub fn get_labels(&self) -> &BTreeMap<u16, String> {
+        &self.labels
+    }
+
+    // Decodes instructions from `address` until the flow leaves them, queueing the targets of
+    // jumps, calls and branches in `pending`
+    fn follow(&mut self, bus: &dyn ReadWritable, mut address: u16, pending: &mut Vec<u16>) {
+        loop {
+            let Ok((instruction, size)) = decode_from(bus, address) else {
+                return;
+            };
+            let start = address as usize;
+            // Instructions cannot wrap around the address space or overlap others
+            let end = start + size;
+            if end > MEM_SIZE
+                || self.kinds[start..end]
+                    .iter()
+                    .any(|&kind| kind != ByteKind::Data)
+            {
+                return;
+            }
+            self.kinds[start] = ByteKind::OpCode;
+            self.kinds[start + 1..end].fill(ByteKind::Operand);
+            self.instructions.insert(address, instruction);
+
+            if let Some(target) = instruction.get_branch_target(address) {
+                self.add_label(target, format!("label_{target:04x}"));
+                pending.push(target);
+            }
+            match (instruction.name, instruction.addressing_mode) {
+                (InstructionName::jsr, _) => {
+                    let target = instruction.operand;
+                    self.add_label(target, format!("sub_{target:04x}"));
+                    pending.push(target);
+                }
+                (InstructionName::jmp, AddressingMode::Absolute) => {
+                    let target = instruction.operand;
+                    self.add_label(target, format!("label_{target:04x}"));
+                    pending.push(target);
+                    return;
+                }
+                // Indirect jumps go wherever the pointer says at the time
+                (
+                    InstructionName::jmp
+                    | InstructionName::rts
+                    | InstructionName::rti
+                    | InstructionName::brk,
+                    _,
+                ) => return,
+                _ => {}
+            }
+            if end == MEM_SIZE {
+                return;
+            }
+            address = end as u16;
+        }
+    }
+
+    // The first name given to an address is kept
+    fn add_label(&mut self, address: u16, name: String) {
+        self.labels.entry(address).or_insert(name);
+    }
+
+    // Labels in the middle of an instruction cannot be defined in the source
+    fn get_label(&self, address: u16) -> Option<&str> {
+        if self.get_kind(address) == ByteKind::Operand {
+            return None;
+        }
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    /// Writes assembly source that the `assembler` module turns back into the same image as
+    /// `bus`. Unreached bytes become `.byte` directives, except for the vectors, which become
+    /// `.word` directives, and runs of zeros, which are skipped with `.org`.
+    pub fn write_source<W: Write>(&self, bus: &dyn ReadWritable, mut writer: W) -> io::Result<()> {
+        // Where the assembler would place the next byte
+        let mut pc = None;
+        let mut address = 0;
+        while address < MEM_SIZE {
+            let addr = address as u16;
+            // The assembler fills whatever the source skips with zeros
+            let zeros = (address..MEM_SIZE)
+                .take_while(|&index| {
+                    self.kinds[index] == ByteKind::Data
+                        && bus.peek(index as u16) == 0
+                        && !self.labels.contains_key(&(index as u16))
+                })
+                .count();
+            if zeros >= MIN_SKIPPED_ZEROS {
+                address += zeros;
+                continue;
+            }
+            if pc != Some(address) {
+                writeln!(writer, ".org ${addr:04x}")?;
+            }
+            if let Some(label) = self.get_label(addr) {
+                writeln!(writer, "{label}:")?;
+            }
+
+            let size = match self.instructions.get(&addr) {
+                Some(instruction) => {
+                    let text = self.format_instruction(bus, addr, instruction);
+                    writeln!(writer, "    {text}")?;
+                    instruction.size() as usize
+                }
+                None if address == NMI_VEC_LOW_ADDR as usize && self.is_vector_table() => {
+                    let words = [NMI_VEC_LOW_ADDR, RESET_VEC_LOW_ADDR, IRQ_VEC_LOW_ADDR]
+                        .into_iter()
+                        .map(|vector| {
+                            let target = read_word(bus, vector);
+                            match self.get_label(target) {
+                                Some(label) => label.to_string(),
+                                None => format!("${target:04x}"),
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    writeln!(writer, "    .word {}", words.join(", "))?;
+                    MEM_SIZE - address
+                }
+                None => {
+                    let end = (address + 1..MEM_SIZE)
+                        .take(BYTES_PER_LINE - 1)
+                        .find(|&index| {
+                            self.kinds[index] != ByteKind::Data
+                                || self.has_label(index, index + 1)
+                                || index == NMI_VEC_LOW_ADDR as usize
+                        })
+                        .unwrap_or((address + BYTES_PER_LINE).min(MEM_SIZE));
+                    let bytes = (address..end)
+                        .map(|index| format!("${:02x}", bus.peek(index as u16)))
+                        .collect::<Vec<_>>();
+                    writeln!(writer, "    .byte {}", bytes.join(", "))?;
+                    end - address
+                }
+            };
+            address += size;
+            pc = Some(address);
+        }
+        Ok(())
+    }
+
+    fn format_instruction(
+        &self,
+        bus: &dyn ReadWritable,
+        address: u16,
+        instruction: &Instruction,
+    ) -> String {
+        if !self.can_reassemble(address, instruction) {
+            let bytes = (0..instruction.size())
+                .map(|offset| format!("${:02x}", bus.peek(address.wrapping_add(offset))))
+                .collect::<Vec<_>>();
+            let text = instruction.disassemble(address);
+            return format!(".byte {} ; {text}", bytes.join(", "));
+        }
+        let target = match (instruction.name, instruction.addressing_mode) {
+            (_, AddressingMode::Relative) => instruction.get_branch_target(address),
+            (InstructionName::jmp | InstructionName::jsr, AddressingMode::Absolute) => {
+                Some(instruction.operand)
+            }
+            _ => None,
+        };
+        match target.and_then(|target| self.get_label(target)) {
+            Some(label) => format!("{} {label}", instruction.name),
+            None if instruction.addressing_mode == AddressingMode::Accumulator => {
+                format!("{} a", instruction.name)
+            }
+            None => instruction.disassemble(address),
+        }
+    }
+
+    // The assembler picks the zero page form of an instruction whenever its operand fits, and
+    // cannot branch across the end of the address space
+    fn can_reassemble(&self, address: u16, instruction: &Instruction) -> bool {
+        let zero_page = match instruction.addressing_mode {
+            AddressingMode::Absolute => AddressingMode::ZeroPage,
+            AddressingMode::AbsoluteX => AddressingMode::ZeroPageX,
+            AddressingMode::AbsoluteY => AddressingMode::ZeroPageY,
+            AddressingMode::Relative => {
+                let offset = instruction.operand as u8 as i8 as i64;
+                return (0..MEM_SIZE as i64).contains(&(address as i64 + 2 + offset));
+            }
+            _ => return true,
+        };
+        instruction.operand > 0xff
+            || !get_registry()
+                .get_instruction_by_name(instruction.name)
+                .get_modes()
+                .contains_key(&zero_page)
+    }
+
+    fn has_label(&self, start: usize, end: usize) -> bool {
+        self.labels
+            .range(start as u16..=(end - 1) as u16)
+            .next()
+            .is_some()
+    }
+
+    fn is_vector_table(&self) -> bool {
+        let start = NMI_VEC_LOW_ADDR as usize;
+        self.kinds[start..]
+            .iter()
+            .all(|&kind| kind == ByteKind::Data)
+            && !self.has_label(start + 1, MEM_SIZE)
+    }
+}
+
+// The reset vector names its handler first when several vectors share one
+const VECTORS: [(&str, u16); 3] = [
+    ("reset", RESET_VEC_LOW_ADDR),
+    ("nmi", NMI_VEC_LOW_ADDR),
+    ("irq", IRQ_VEC_LOW_ADDR),
+];
+const BYTES_PER_LINE: usize = 8;
+const MIN_SKIPPED_ZEROS: usize = 16;
+
+fn read_word(bus: &dyn ReadWritable, address: u16) -> u16 {
+    let low = bus.peek(address) as u16;
+    let high = bus.peek(address.wrapping_add(1)) as u16;
+    (high << 8) | low
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::mem::Memory;
+
+    // Code interleaved with a table, an instruction the assembler would shorten, unknown op
+    // codes and a run of zeros
+    const MIXED_SOURCE: &str = "
+.org $8000
+reset:
+    ldx #$00
+loop:
+    lda table,x
+    sta $0200,x
+    inx
+    cpx #4
+    bne loop
+    jsr copy
+    jmp ($0010)
+table:
+    .byte $de, $ad, $be, $ef, $02, $ff
+copy:
+    asl a
+    .byte $ad, $12, $00 ; lda $0012
+    rts
+nmi:
+    rti
+.org $9000
+    .byte $01
+.org $9020
+    .byte $4c, $00, $80
+.org $fffa
+    .word nmi, reset, nmi
+";
+
+    #[test]
+    fn unknown_op_codes_and_truncated_instructions_are_single_bytes() {
+        let rom = [0xa9, 0x01, 0x02, 0xd0, 0xfc, 0xad, 0x34];
+        let lines = Disassembler::new_from_bytes(&rom, 0x8000)
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "8000  a9 01     lda #$01",
+                "8002  02        .byte $02",
+                "8003  d0 fc     bne $8001",
+                "8005  ad        .byte $ad",
+                "8006  34        .byte $34",
+            ]
+        );
+    }
+
+    #[test]
+    fn bus_ranges_end_with_the_instruction_that_starts_in_them() {
+        let mut memory = Memory::new();
+        for (offset, byte) in [0xea, 0x8d, 0x00, 0x02].into_iter().enumerate() {
+            memory.write(0x0300 + offset as u16, byte);
+        }
+        let lines = Disassembler::new(&memory, 0x0300..=0x0301)
+            .map(|line| (line.address, line.bytes))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [(0x0300, vec![0xea]), (0x0301, vec![0x8d, 0x00, 0x02])]
+        );
+    }
+
+    #[test]
+    fn write_source_reassembles_to_the_same_image() {
+        let image = assemble(MIXED_SOURCE).unwrap();
+        let memory = Memory::new_from_bytes(image);
+        let disassembly = Disassembly::new(&memory, &[]);
+        assert_eq!(disassembly.get_kind(0x8000), ByteKind::OpCode);
+        assert_eq!(disassembly.get_kind(0x8013), ByteKind::Data);
+        assert_eq!(disassembly.get_kind(0x9020), ByteKind::Data);
+
+        let mut source = Vec::new();
+        disassembly.write_source(&memory, &mut source).unwrap();
+        let source = String::from_utf8(source).unwrap();
+        let reassembled = assemble(&source).unwrap();
+        let mismatch = (0..MEM_SIZE).find(|&address| reassembled[address] != image[address]);
+        assert_eq!(mismatch, None, "{source}");
+    }
+}
@@ -1,301 +1,1091 @@
-use std::cell::{Ref, RefCell, RefMut};
-use std::rc::Rc;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
 
-use crate::decoder::Decoder;
-use crate::instruction::{AddressingMode, Instruction, InstructionName};
+use crate::breakpoint::{Access, BreakpointId, Breakpoints};
+use crate::callstack::{Backtrace, CallFrame, CallStack, CallStackChange, FrameKind};
+use crate::decoder::{decode_from, get_registry, DecodeError};
+use crate::history::{History, HistoryEntry};
+use crate::instruction::{AddressingMode, Instruction, InstructionName, InstructionRegistry};
+use crate::observer::{EmulatorObserver, ObserverId, Observers};
 use crate::readwritable::ReadWritable;
 use crate::regs::{CpuFlags, Regs};
+use crate::snapshot::{
+    read_bool, read_u16, read_u32, read_u64, read_u8, write_u16, write_u32, write_u64, write_u8,
+    SnapshotError, SNAPSHOT_MAGIC, SNAPSHOT_VERSION,
+};
+use crate::trace::{TraceFormat, TraceLine, Tracer};
 
+pub const NMI_VEC_LOW_ADDR: u16 = 0xfffa;
+pub const NMI_VEC_HIGH_ADDR: u16 = 0xfffb;
 pub const RESET_VEC_LOW_ADDR: u16 = 0xfffc;
 pub const RESET_VEC_HIGH_ADDR: u16 = 0xfffd;
 pub const IRQ_VEC_LOW_ADDR: u16 = 0xfffe;
 pub const IRQ_VEC_HIGH_ADDR: u16 = 0xffff;
 
-pub struct Emulator {
-    decoder: Decoder,
-    regs: Rc<RefCell<Regs>>,
-    bus: Rc<RefCell<Box<dyn ReadWritable>>>,
-    stop_signalled: bool,
+const INTERRUPT_CYCLES: u8 = 7;
+const ILLEGAL_OPCODE_CYCLES: u8 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EmulatorErrorKind {
+    IllegalOpcode,
+    StackOverflow,
+    StackUnderflow,
+    InvalidAddressingMode(AddressingMode),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EmulatorError {
+    /// The address of the instruction, or of the interrupted instruction while servicing an
+    /// interrupt
+    pub pc: u16,
+    /// `None` while servicing an interrupt
+    pub opcode: Option<u8>,
+    pub kind: EmulatorErrorKind,
 }
 
-impl Emulator {
-    pub fn new(bus: Box<dyn ReadWritable>) -> Self {
-        let bus_rc = Rc::new(RefCell::new(bus));
-        let regs = Rc::new(RefCell::new(Regs::new()));
-        let next_byte = {
-            let bus_rc = bus_rc.clone();
-            let regs = regs.clone();
-            move || {
-                let bus = bus_rc.borrow();
-                let mut regs = regs.borrow_mut();
-                let byte = bus.read(regs.pc);
-                regs.pc += 1;
-                byte
+impl Display for EmulatorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let cause = match self.kind {
+            EmulatorErrorKind::IllegalOpcode => "illegal opcode".to_string(),
+            EmulatorErrorKind::StackOverflow => "stack overflow".to_string(),
+            EmulatorErrorKind::StackUnderflow => "stack underflow".to_string(),
+            EmulatorErrorKind::InvalidAddressingMode(mode) => {
+                format!("cannot get an address when addressing_mode={mode}")
             }
         };
-        let decoder = Decoder::new(Box::new(next_byte));
+        match self.opcode {
+            Some(opcode) => write!(f, "{cause} at {:#06x} (op code {:#04x})", self.pc, opcode),
+            None => write!(
+                f,
+                "{cause} at {:#06x} while servicing an interrupt",
+                self.pc
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FaultAction {
+    Fault,
+    Continue,
+}
+
+/// What to do when a program executes an illegal opcode or wraps the stack pointer around.
+#[derive(Default)]
+pub enum FaultPolicy {
+    /// Stop and return an `EmulatorError`
+    #[default]
+    Fault,
+    /// Carry on like the hardware would: the stack pointer wraps around and illegal opcodes are
+    /// skipped as one byte nops
+    Continue,
+    /// Let the callback decide. It may also change the registers, e.g. to jump to a handler.
+    Callback(FaultCallback),
+}
+
+pub type FaultCallback = Box<dyn FnMut(&EmulatorError, &mut Regs) -> FaultAction>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InterruptKind {
+    Reset,
+    Nmi,
+    Irq,
+    Brk,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct StepResult {
+    /// The address the instruction was fetched from
+    pub address: u16,
+    /// `None` when an illegal opcode was skipped as a nop
+    pub instruction: Option<Instruction>,
+    /// The address the operand resolved to: the byte read or written, or the jump or branch
+    /// target
+    pub effective_address: Option<u16>,
+    /// Includes the cycles spent servicing an interrupt before the instruction
+    pub cycles: u8,
+    /// The interrupt serviced before the instruction, or `Brk` if the instruction was a `brk`
+    pub interrupt: Option<InterruptKind>,
+    /// Whether the instruction was a `brk`, which is where `run` stops
+    pub halted: bool,
+    /// The first watchpoint the instruction's reads or writes fired
+    pub watchpoint: Option<BreakpointId>,
+    /// Whether a branch instruction branched, `None` for every other instruction
+    pub branch_taken: Option<bool>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The instruction or cycle budget ran out
+    BudgetExhausted,
+    /// A `brk` was executed
+    Break,
+    /// The condition given to `run_until` held
+    Condition,
+    /// A breakpoint stopped execution before an instruction, or a watchpoint after it
+    Breakpoint(BreakpointId),
+    /// The instruction at this address jumps or branches to itself, e.g. `jmp *`, with no
+    /// interrupt that could get the program out of it
+    TrapDetected(u16),
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::BudgetExhausted => write!(f, "budget exhausted"),
+            StopReason::Break => write!(f, "brk"),
+            StopReason::Condition => write!(f, "condition met"),
+            StopReason::Breakpoint(id) => write!(f, "breakpoint {id}"),
+            StopReason::TrapDetected(addr) => write!(f, "trapped at {addr:#06x}"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum CpuVariant {
+    #[default]
+    Nmos6502,
+    /// The NES CPU: a 6502 with the decimal mode circuitry removed, so `sed` has no effect on
+    /// `adc` and `sbc`.
+    Ricoh2A03,
+    /// The CMOS 65C02. Only its fix for the `jmp ($xxff)` page wrap is modelled, the extra
+    /// instructions and decimal mode flag changes are not.
+    Cmos65C02,
+}
+
+impl CpuVariant {
+    pub const fn has_decimal_mode(&self) -> bool {
+        match self {
+            CpuVariant::Nmos6502 | CpuVariant::Cmos65C02 => true,
+            CpuVariant::Ricoh2A03 => false,
+        }
+    }
+
+    pub const fn has_jmp_indirect_bug(&self) -> bool {
+        match self {
+            CpuVariant::Nmos6502 | CpuVariant::Ricoh2A03 => true,
+            CpuVariant::Cmos65C02 => false,
+        }
+    }
+}
+
+/// An emulator whose bus is type erased, for callers that pick the bus at runtime
+pub type DynEmulator = Emulator<Box<dyn ReadWritable>>;
+
+pub struct Emulator<B: ReadWritable> {
+    // Shared by every emulator, since building the op code table is not free
+    registry: &'static InstructionRegistry,
+    regs: Regs,
+    bus: B,
+    stop_signalled: bool,
+    cycles: u64,
+    variant: CpuVariant,
+    irq_asserted: bool,
+    nmi_pending: bool,
+    illegal_opcode_policy: FaultPolicy,
+    stack_policy: FaultPolicy,
+    current_pc: u16,
+    current_opcode: Option<u8>,
+    breakpoints: Breakpoints,
+    watchpoint_hit: Option<BreakpointId>,
+    // The address execution last stopped at because of a breakpoint, which is not hit again when
+    // execution resumes from there
+    resume_addr: Option<u16>,
+    history: Option<History>,
+    tracer: Option<Tracer>,
+    observers: Observers,
+    call_stack: CallStack,
+}
+
+impl<B: ReadWritable> Emulator<B> {
+    pub fn new(bus: B) -> Self {
         Self {
-            decoder,
-            regs,
-            bus: bus_rc,
+            registry: get_registry(),
+            regs: Regs::new(),
+            bus,
             stop_signalled: false,
+            cycles: 0,
+            variant: CpuVariant::default(),
+            irq_asserted: false,
+            nmi_pending: false,
+            illegal_opcode_policy: FaultPolicy::default(),
+            stack_policy: FaultPolicy::default(),
+            current_pc: 0,
+            current_opcode: None,
+            breakpoints: Breakpoints::new(),
+            watchpoint_hit: None,
+            resume_addr: None,
+            history: None,
+            tracer: None,
+            observers: Observers::new(),
+            call_stack: CallStack::new(),
         }
     }
 
-    pub fn run_until_break(&mut self) {
+    pub fn run_until_break(&mut self) -> Option<BreakpointId> {
         self.run(|_, _| true)
     }
 
-    pub fn run<F: Fn(&Regs, &dyn ReadWritable) -> bool>(&mut self, on_break: F) {
+    pub fn run<F: Fn(&Regs, &dyn ReadWritable) -> bool>(
+        &mut self,
+        on_break: F,
+    ) -> Option<BreakpointId> {
+        self.try_run(on_break).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_run_until_break(&mut self) -> Result<Option<BreakpointId>, EmulatorError> {
+        self.try_run(|_, _| true)
+    }
+
+    /// Runs from the reset vector, calling `on_break` after every `brk` until it returns true.
+    /// Returns the id of the breakpoint that stopped execution before that, if any.
+    pub fn try_run<F: Fn(&Regs, &dyn ReadWritable) -> bool>(
+        &mut self,
+        on_break: F,
+    ) -> Result<Option<BreakpointId>, EmulatorError> {
         self.stop_signalled = false;
+        self.resume_addr = None;
         let reset_addr = self.get_reset_addr();
         self.set_pc(reset_addr);
         loop {
-            while !self.stop_signalled {
-                self.execute_next();
+            if let Some(id) = self.stop_at_breakpoint() {
+                return Ok(Some(id));
+            }
+            let result = self.execute_next()?;
+            if let Some(id) = result.watchpoint {
+                return Ok(Some(id));
             }
-            self.stop_signalled = false;
-            if on_break(&*self.get_regs(), &**self.get_bus()) {
-                break;
+            if result.halted && on_break(&self.regs, &self.bus) {
+                return Ok(None);
             }
         }
     }
 
-    pub fn get_regs(&self) -> Ref<Regs> {
-        self.regs.borrow()
+    /// Executes a single instruction from the current program counter, servicing any pending
+    /// interrupt first. Unlike `run`, the reset vector is not loaded and execution breakpoints
+    /// are not checked.
+    pub fn step(&mut self) -> StepResult {
+        self.try_step().unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_step(&mut self) -> Result<StepResult, EmulatorError> {
+        self.execute_next()
     }
 
-    pub fn get_regs_mut(&mut self) -> RefMut<Regs> {
-        self.regs.borrow_mut()
+    /// Runs from the current program counter until `n` instructions have executed.
+    pub fn run_for_instructions(&mut self, n: u64) -> Result<StopReason, EmulatorError> {
+        self.run_with(|_, executed| executed >= n)
+            .map(|reason| reason.unwrap_or(StopReason::BudgetExhausted))
     }
 
-    pub fn get_bus(&self) -> Ref<Box<dyn ReadWritable>> {
-        self.bus.borrow()
+    /// Runs from the current program counter until at least `n` cycles have passed. The last
+    /// instruction may overshoot the budget.
+    pub fn run_for_cycles(&mut self, n: u64) -> Result<StopReason, EmulatorError> {
+        let end = self.cycles.saturating_add(n);
+        self.run_with(|emulator, _| emulator.cycles >= end)
+            .map(|reason| reason.unwrap_or(StopReason::BudgetExhausted))
     }
 
-    pub fn get_bus_mut(&mut self) -> RefMut<Box<dyn ReadWritable>> {
-        self.bus.borrow_mut()
+    /// Runs from the current program counter until `condition` holds after an instruction.
+    pub fn run_until<F: FnMut(&Emulator<B>) -> bool>(
+        &mut self,
+        mut condition: F,
+    ) -> Result<StopReason, EmulatorError> {
+        self.run_with(|emulator, _| condition(emulator))
+            .map(|reason| reason.unwrap_or(StopReason::Condition))
+    }
+
+    // Steps until a `brk`, a trap or `done` returns true, in which case `None` is returned.
+    // `done` is also given the number of instructions executed so far.
+    fn run_with<F: FnMut(&Emulator<B>, u64) -> bool>(
+        &mut self,
+        mut done: F,
+    ) -> Result<Option<StopReason>, EmulatorError> {
+        let mut executed = 0;
+        if done(self, executed) {
+            return Ok(None);
+        }
+        let resume_addr = self.resume_addr.take();
+        loop {
+            if executed > 0 || resume_addr != Some(self.get_regs().pc) {
+                if let Some(id) = self.stop_at_breakpoint() {
+                    return Ok(Some(StopReason::Breakpoint(id)));
+                }
+            }
+            let result = self.execute_next()?;
+            executed += 1;
+            if let Some(id) = result.watchpoint {
+                return Ok(Some(StopReason::Breakpoint(id)));
+            }
+            if result.halted {
+                return Ok(Some(StopReason::Break));
+            }
+            if self.is_trapped(&result) {
+                return Ok(Some(StopReason::TrapDetected(result.address)));
+            }
+            if done(self, executed) {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Checks the execution breakpoints before the instruction at the program counter. Where
+    // execution stopped is remembered, so that resuming does not hit the same breakpoint again.
+    fn stop_at_breakpoint(&mut self) -> Option<BreakpointId> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+        let pc = self.get_regs().pc;
+        let op_code = self.get_bus().peek(pc);
+        let name = self
+            .registry
+            .get_instruction_by_op_code(op_code, 0)
+            .map(|ins| ins.name);
+        let id = self
+            .breakpoints
+            .check_execution(pc, op_code, name, &self.regs, &self.bus)?;
+        self.resume_addr = Some(pc);
+        Some(id)
+    }
+
+    // A jump or branch to itself can only be left through an interrupt, and no interrupt is
+    // pending that could be taken
+    fn is_trapped(&self, result: &StepResult) -> bool {
+        let Some(ins) = result.instruction else {
+            return false;
+        };
+        if ins.name != InstructionName::jmp && ins.addressing_mode != AddressingMode::Relative {
+            return false;
+        }
+        let regs = self.get_regs();
+        let interruptible =
+            self.nmi_pending || (self.irq_asserted && !regs.flags.contains(CpuFlags::INT_DISABLE));
+        regs.pc == result.address && !interruptible
+    }
+
+    pub fn set_illegal_opcode_policy(&mut self, policy: FaultPolicy) {
+        self.illegal_opcode_policy = policy;
+    }
+
+    pub fn set_stack_policy(&mut self, policy: FaultPolicy) {
+        self.stack_policy = policy;
+    }
+
+    /// Pulls the IRQ line low. The interrupt is taken before the next instruction whenever
+    /// `INT_DISABLE` is clear, for as long as the line stays asserted.
+    pub fn assert_irq(&mut self) {
+        self.irq_asserted = true;
+    }
+
+    pub fn release_irq(&mut self) {
+        self.irq_asserted = false;
+    }
+
+    pub fn is_irq_asserted(&self) -> bool {
+        self.irq_asserted
+    }
+
+    /// Signals a falling edge on the NMI line. The interrupt is taken before the next instruction
+    /// regardless of `INT_DISABLE`; edges that arrive before then are merged into one.
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    pub fn is_nmi_pending(&self) -> bool {
+        self.nmi_pending
+    }
+
+    /// Runs the reset sequence: the stack pointer moves down three bytes without writing,
+    /// `INT_DISABLE` is set and execution continues at the reset vector.
+    pub fn reset(&mut self) {
+        self.clear_history();
+        self.call_stack.clear();
+        self.nmi_pending = false;
+        self.stop_signalled = false;
+        let reset_addr = self.get_reset_addr();
+        let regs = self.get_regs_mut();
+        regs.sp = regs.sp.wrapping_sub(3);
+        regs.flags.insert(CpuFlags::INT_DISABLE);
+        regs.pc = reset_addr;
+        self.cycles += INTERRUPT_CYCLES as u64;
+        self.observers.on_interrupt(InterruptKind::Reset);
+    }
+
+    pub fn get_regs(&self) -> &Regs {
+        &self.regs
+    }
+
+    pub fn get_regs_mut(&mut self) -> &mut Regs {
+        &mut self.regs
+    }
+
+    /// Starts recording the last `capacity` instructions so that they can be undone with
+    /// `step_back` and `rewind_to_cycle`. Writes made through `get_bus_mut` are not recorded.
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    pub fn get_history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Undoes the last recorded instruction. Returns false if there is nothing to undo.
+    pub fn step_back(&mut self) -> bool {
+        let Some(entry) = self.history.as_mut().and_then(History::pop) else {
+            return false;
+        };
+        for &(address, old) in entry.writes.iter().rev() {
+            self.bus.write(address, old);
+        }
+        *self.get_regs_mut() = entry.regs;
+        for change in entry.call_stack_changes.into_iter().rev() {
+            self.call_stack.revert(change);
+        }
+        self.cycles = entry.cycles;
+        self.nmi_pending = entry.nmi_pending;
+        self.stop_signalled = entry.stop_signalled;
+        self.resume_addr = None;
+        true
+    }
+
+    /// Undoes instructions until the cycle counter is at most `cycle`, i.e. back to the start
+    /// of the instruction that was running at that cycle. Returns false and changes nothing if
+    /// the history does not go back that far.
+    pub fn rewind_to_cycle(&mut self, cycle: u64) -> bool {
+        if self.cycles <= cycle {
+            return true;
+        }
+        match self.history.as_ref().and_then(History::get_oldest_cycle) {
+            Some(oldest) if oldest <= cycle => {}
+            _ => return false,
+        }
+        while self.cycles > cycle && self.step_back() {}
+        true
+    }
+
+    fn clear_history(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    /// Writes a line to `writer` before every instruction, after any interrupt has been serviced
+    pub fn enable_tracing(&mut self, writer: Box<dyn Write>, format: TraceFormat) {
+        self.tracer = Some(Tracer::new(writer, format));
+    }
+
+    /// Returns the tracer so that its writer can be flushed and checked for errors
+    pub fn disable_tracing(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    pub fn get_tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    /// The trace line for the instruction at the program counter. Unlike the lines `Tracer`
+    /// writes, a pending interrupt has not been serviced yet.
+    pub fn get_trace_line(&self) -> TraceLine {
+        self.get_trace_line_at(self.get_regs().pc, self.cycles)
+    }
+
+    /// Attaches an observer that is told about every instruction, memory access and interrupt
+    /// from now on
+    pub fn add_observer(&mut self, observer: Box<dyn EmulatorObserver>) -> ObserverId {
+        self.observers.add(observer)
+    }
+
+    pub fn remove_observer(&mut self, id: ObserverId) -> Option<Box<dyn EmulatorObserver>> {
+        self.observers.remove(id)
+    }
+
+    /// The subroutines and interrupt handlers execution is in, from the call stack kept
+    /// alongside the hardware stack. The first line is the program counter, which after an
+    /// `EmulatorError` is past the faulting instruction at `EmulatorError::pc`.
+    pub fn backtrace(&self) -> Backtrace {
+        Backtrace {
+            pc: self.get_regs().pc,
+            frames: self.call_stack.get_frames().to_vec(),
+        }
+    }
+
+    /// Also holds the warnings about returns that did not match the call stack
+    pub fn get_call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    pub fn get_call_stack_mut(&mut self) -> &mut CallStack {
+        &mut self.call_stack
+    }
+
+    /// Breakpoints stop `run`, `run_for_instructions`, `run_for_cycles` and `run_until`
+    pub fn get_breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn get_breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    /// Writes the registers, cycle counter, interrupt lines and stop flag, and the bus contents
+    /// if the bus implements `SaveState`.
+    pub fn save_snapshot<W: Write>(&self, mut writer: W) -> Result<(), SnapshotError> {
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        write_u16(&mut writer, SNAPSHOT_VERSION)?;
+
+        let regs = *self.get_regs();
+        write_u16(&mut writer, regs.pc)?;
+        write_u8(&mut writer, regs.sp)?;
+        write_u8(&mut writer, regs.a)?;
+        write_u8(&mut writer, regs.x)?;
+        write_u8(&mut writer, regs.y)?;
+        write_u8(&mut writer, regs.flags.bits())?;
+
+        write_u64(&mut writer, self.cycles)?;
+        let variant = match self.variant {
+            CpuVariant::Nmos6502 => 0,
+            CpuVariant::Ricoh2A03 => 1,
+            CpuVariant::Cmos65C02 => 2,
+        };
+        write_u8(&mut writer, variant)?;
+        write_u8(&mut writer, self.irq_asserted as u8)?;
+        write_u8(&mut writer, self.nmi_pending as u8)?;
+        write_u8(&mut writer, self.stop_signalled as u8)?;
+
+        match self.get_bus().as_save_state() {
+            Some(device) => {
+                let state = device.save_state();
+                write_u8(&mut writer, 1)?;
+                write_u32(&mut writer, state.len() as u32)?;
+                writer.write_all(&state)?;
+            }
+            None => write_u8(&mut writer, 0)?,
+        }
+        Ok(())
+    }
+
+    /// Restores a snapshot written by `save_snapshot`. The emulator is left untouched if the
+    /// snapshot is rejected, and the bus is left as it is if the snapshot has no device state.
+    pub fn load_snapshot<R: Read>(&mut self, mut reader: R) -> Result<(), SnapshotError> {
+        let mut magic = [0; 4];
+        if reader.read_exact(&mut magic).is_err() || magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = read_u16(&mut reader)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let regs = Regs {
+            pc: read_u16(&mut reader)?,
+            sp: read_u8(&mut reader)?,
+            a: read_u8(&mut reader)?,
+            x: read_u8(&mut reader)?,
+            y: read_u8(&mut reader)?,
+            flags: CpuFlags::from_bits_retain(read_u8(&mut reader)?),
+        };
+
+        let cycles = read_u64(&mut reader)?;
+        let variant = match read_u8(&mut reader)? {
+            0 => CpuVariant::Nmos6502,
+            1 => CpuVariant::Ricoh2A03,
+            2 => CpuVariant::Cmos65C02,
+            variant => {
+                return Err(SnapshotError::InvalidData(format!(
+                    "unknown cpu variant {variant}"
+                )))
+            }
+        };
+        let irq_asserted = read_bool(&mut reader)?;
+        let nmi_pending = read_bool(&mut reader)?;
+        let stop_signalled = read_bool(&mut reader)?;
+
+        if read_bool(&mut reader)? {
+            let len = read_u32(&mut reader)? as u64;
+            let mut state = Vec::new();
+            reader.by_ref().take(len).read_to_end(&mut state)?;
+            if state.len() as u64 != len {
+                return Err(SnapshotError::InvalidData(
+                    "unexpected end of snapshot".to_string(),
+                ));
+            }
+            let bus = self.get_bus_mut();
+            let device = bus
+                .as_save_state_mut()
+                .ok_or(SnapshotError::MissingDevice)?;
+            device.load_state(&state)?;
+        }
+
+        *self.get_regs_mut() = regs;
+        self.cycles = cycles;
+        self.variant = variant;
+        self.irq_asserted = irq_asserted;
+        self.nmi_pending = nmi_pending;
+        self.stop_signalled = stop_signalled;
+        self.clear_history();
+        self.call_stack.clear();
+        Ok(())
+    }
+
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn get_variant(&self) -> CpuVariant {
+        self.variant
+    }
+
+    pub fn set_variant(&mut self, variant: CpuVariant) {
+        self.variant = variant;
+    }
+
+    pub fn get_bus(&self) -> &B {
+        &self.bus
+    }
+
+    pub fn get_bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn into_bus(self) -> B {
+        self.bus
     }
 
     fn set_pc(&mut self, pc: u16) {
         self.get_regs_mut().pc = pc;
     }
 
-    fn read_from_stack(&self) -> u8 {
-        self.get_bus().read(self.get_regs().sp as u16 + 0x100)
+    fn read_from_stack(&mut self) -> u8 {
+        let addr = self.get_regs().sp as u16 + 0x100;
+        self.read_bus(addr)
     }
 
     fn write_to_stack(&mut self, byte: u8) {
         let addr = self.get_regs().sp as u16 + 0x100;
-        self.get_bus_mut().write(addr, byte);
+        self.write_bus(addr, byte);
     }
 
-    fn get_reset_addr(&self) -> u16 {
+    pub fn get_reset_addr(&self) -> u16 {
         let low = self.get_bus().read(RESET_VEC_LOW_ADDR) as u16;
         let high = self.get_bus().read(RESET_VEC_HIGH_ADDR) as u16;
         (high << 8) | low
     }
 
-    fn get_irq_addr(&self) -> u16 {
+    pub fn get_nmi_addr(&self) -> u16 {
+        let low = self.get_bus().read(NMI_VEC_LOW_ADDR) as u16;
+        let high = self.get_bus().read(NMI_VEC_HIGH_ADDR) as u16;
+        (high << 8) | low
+    }
+
+    pub fn get_irq_addr(&self) -> u16 {
         let low = self.get_bus().read(IRQ_VEC_LOW_ADDR) as u16;
         let high = self.get_bus().read(IRQ_VEC_HIGH_ADDR) as u16;
         (high << 8) | low
     }
 
-    fn decode_next(&mut self) -> Instruction {
-        self.decoder.decode_next()
+    // Reads the instruction at `pc` without going through the decoder or the watchpoints
+    fn get_trace_line_at(&self, pc: u16, cycles: u64) -> TraceLine {
+        let bus = self.get_bus();
+        let instruction = decode_from(bus, pc).ok().map(|(ins, _)| ins);
+        let size = instruction.map_or(1, |ins| ins.size());
+        let bytes = (0..size)
+            .map(|offset| bus.peek(pc.wrapping_add(offset)))
+            .collect::<Vec<_>>();
+        TraceLine {
+            address: pc,
+            bytes,
+            instruction,
+            regs: *self.get_regs(),
+            cycles,
+        }
+    }
+
+    #[inline]
+    fn fetch_byte(&mut self) -> u8 {
+        let byte = self.bus.read(self.regs.pc);
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        byte
+    }
+
+    #[inline]
+    fn decode_next(&mut self) -> Result<Instruction, DecodeError> {
+        let op_code = self.fetch_byte();
+        self.current_opcode = Some(op_code);
+        let mut instruction = self
+            .registry
+            .get_instruction_by_op_code(op_code, 0)
+            .ok_or(DecodeError::UnknownOpCode(op_code))?;
+        instruction.operand = match instruction.addressing_mode.operand_size() {
+            0 => 0,
+            1 => self.fetch_byte() as u16,
+            _ => {
+                let low = self.fetch_byte() as u16;
+                let high = self.fetch_byte() as u16;
+                (high << 8) | low
+            }
+        };
+        Ok(instruction)
+    }
+
+    fn error(&self, kind: EmulatorErrorKind) -> EmulatorError {
+        EmulatorError {
+            pc: self.current_pc,
+            opcode: self.current_opcode,
+            kind,
+        }
     }
 
-    fn get_absolute_address(&self, mode: AddressingMode, address: u16) -> u16 {
-        match mode {
-            AddressingMode::Implicit => {
-                panic!("Cannot get an address when addressing_mode=Implicit")
+    fn fault(&mut self, kind: EmulatorErrorKind) -> Result<(), EmulatorError> {
+        let error = self.error(kind);
+        let policy = match kind {
+            EmulatorErrorKind::IllegalOpcode => &mut self.illegal_opcode_policy,
+            EmulatorErrorKind::StackOverflow | EmulatorErrorKind::StackUnderflow => {
+                &mut self.stack_policy
             }
-            AddressingMode::Accumulator => {
-                panic!("Cannot get an address when addressing_mode=Accumulator")
+            EmulatorErrorKind::InvalidAddressingMode(_) => return Err(error),
+        };
+        let action = match policy {
+            FaultPolicy::Fault => FaultAction::Fault,
+            FaultPolicy::Continue => FaultAction::Continue,
+            FaultPolicy::Callback(callback) => callback(&error, &mut self.regs),
+        };
+        match action {
+            FaultAction::Fault => Err(error),
+            FaultAction::Continue => Ok(()),
+        }
+    }
+
+    // Resolves the operand of an instruction to the address it touches, once per instruction
+    fn get_operand_address(&mut self, ins: &Instruction) -> Option<u16> {
+        match ins.addressing_mode {
+            AddressingMode::Implicit | AddressingMode::Accumulator | AddressingMode::Immediate => {
+                None
+            }
+            AddressingMode::ZeroPage => Some(ins.operand & 0xff),
+            AddressingMode::ZeroPageX => {
+                Some((ins.operand as u8).wrapping_add(self.get_regs().x) as u16)
+            }
+            AddressingMode::ZeroPageY => {
+                Some((ins.operand as u8).wrapping_add(self.get_regs().y) as u16)
             }
-            AddressingMode::Immediate => {
-                panic!("Cannot get an address when addressing_mode=Immediate")
+            AddressingMode::Relative => {
+                // The operand is a signed displacement from the next instruction
+                let offset = ins.operand as u8 as i8;
+                Some(self.get_regs().pc.wrapping_add(offset as u16))
             }
-            AddressingMode::ZeroPage => address,
-            AddressingMode::ZeroPageX => address + self.get_regs().x as u16,
-            AddressingMode::ZeroPageY => address + self.get_regs().y as u16,
-            AddressingMode::Relative => self.get_regs().pc + address,
-            AddressingMode::Absolute => address,
-            AddressingMode::AbsoluteX => address + self.get_regs().x as u16,
-            AddressingMode::AbsoluteY => address + self.get_regs().y as u16,
+            AddressingMode::Absolute => Some(ins.operand),
+            AddressingMode::AbsoluteX => Some(ins.operand.wrapping_add(self.get_regs().x as u16)),
+            AddressingMode::AbsoluteY => Some(ins.operand.wrapping_add(self.get_regs().y as u16)),
             AddressingMode::Indirect => {
-                let mut addr = self.read_byte(AddressingMode::Absolute, address) as u16;
-                addr |= (self.read_byte(AddressingMode::Absolute, address + 1) as u16) << 8;
-                addr
+                // The NMOS part never carries into the high byte of the pointer, so
+                // jmp ($xxff) reads its high byte from $xx00
+                let address = ins.operand;
+                let high_address = if self.variant.has_jmp_indirect_bug() && address & 0xff == 0xff
+                {
+                    address & 0xff00
+                } else {
+                    address.wrapping_add(1)
+                };
+                let mut addr = self.read_bus(address) as u16;
+                addr |= (self.read_bus(high_address) as u16) << 8;
+                Some(addr)
             }
             AddressingMode::IndirectX => {
-                let address = address + self.get_regs().x as u16;
-                let mut addr = self.read_byte(AddressingMode::Absolute, address) as u16;
-                addr |= (self.read_byte(AddressingMode::Absolute, address + 1) as u16) << 8;
-                addr
+                let pointer = (ins.operand as u8).wrapping_add(self.get_regs().x);
+                Some(self.read_zero_page_word(pointer))
             }
             AddressingMode::IndirectY => {
-                let mut addr = self.read_byte(AddressingMode::Absolute, address) as u16;
-                addr |= (self.read_byte(AddressingMode::Absolute, address + 1) as u16) << 8;
-                addr + self.get_regs().y as u16
+                let addr = self.read_zero_page_word(ins.operand as u8);
+                Some(addr.wrapping_add(self.get_regs().y as u16))
             }
         }
     }
 
-    fn read_byte(&self, mode: AddressingMode, address: u16) -> u8 {
-        if mode == AddressingMode::Accumulator {
-            return self.get_regs().a;
-        }
-        if mode == AddressingMode::Immediate {
-            return address as u8;
+    fn get_target_address(
+        &self,
+        ins: &Instruction,
+        address: Option<u16>,
+    ) -> Result<u16, EmulatorError> {
+        address.ok_or_else(|| {
+            self.error(EmulatorErrorKind::InvalidAddressingMode(
+                ins.addressing_mode,
+            ))
+        })
+    }
+
+    fn read_zero_page_word(&mut self, pointer: u8) -> u16 {
+        let mut addr = self.read_bus(pointer as u16) as u16;
+        addr |= (self.read_bus(pointer.wrapping_add(1) as u16) as u16) << 8;
+        addr
+    }
+
+    // Every data access goes through these two so that watchpoints see it
+    fn read_bus(&mut self, address: u16) -> u8 {
+        let hit = self
+            .breakpoints
+            .check_access(Access::Read, address, &self.regs, &self.bus);
+        self.watchpoint_hit = self.watchpoint_hit.or(hit);
+        let value = self.get_bus().read(address);
+        self.observers.on_read(address, value);
+        value
+    }
+
+    fn write_bus(&mut self, address: u16, byte: u8) {
+        let hit = self
+            .breakpoints
+            .check_access(Access::Write, address, &self.regs, &self.bus);
+        self.watchpoint_hit = self.watchpoint_hit.or(hit);
+        if self.history.is_some() || !self.observers.is_empty() {
+            // The program never reads the old value, so memory-mapped I/O must not see a read
+            let old = self.bus.peek(address);
+            if let Some(history) = &mut self.history {
+                history.record_write(address, old);
+            }
+            self.observers.on_write(address, old, byte);
         }
-        let absolute_address = self.get_absolute_address(mode, address);
-        self.get_bus().read(absolute_address)
+        self.get_bus_mut().write(address, byte);
     }
 
-    fn write_byte(&mut self, mode: AddressingMode, address: u16, byte: u8) {
-        if mode == AddressingMode::Accumulator {
-            self.get_regs_mut().a = byte;
-            return;
+    fn read_operand(&mut self, ins: &Instruction, address: Option<u16>) -> u8 {
+        match address {
+            Some(address) => self.read_bus(address),
+            None if ins.addressing_mode == AddressingMode::Accumulator => self.get_regs().a,
+            None => ins.operand as u8,
         }
-        let absolute_address = self.get_absolute_address(mode, address);
-        self.get_bus_mut().write(absolute_address, byte);
     }
 
-    fn push(&mut self, byte: u8) {
-        if self.get_regs().sp == 0 {
-            panic!("Ran out of stack");
+    fn write_operand(&mut self, ins: &Instruction, address: Option<u16>, byte: u8) {
+        match address {
+            Some(address) => self.write_bus(address, byte),
+            None if ins.addressing_mode == AddressingMode::Accumulator => {
+                self.get_regs_mut().a = byte
+            }
+            None => {}
         }
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), EmulatorError> {
         self.write_to_stack(byte);
+        let address = self.get_regs().sp as u16 + 0x100;
+        self.observers.on_stack_push(address, byte);
 
-        self.get_regs_mut().sp -= 1;
+        let regs = self.get_regs_mut();
+        regs.sp = regs.sp.wrapping_sub(1);
+        // Writing to $0100 is fine, moving the stack pointer past it is not
+        if regs.sp == 0xff {
+            self.fault(EmulatorErrorKind::StackOverflow)?;
+        }
+        Ok(())
     }
 
-    fn pull(&mut self) -> u8 {
+    fn pull(&mut self) -> Result<u8, EmulatorError> {
         if self.get_regs().sp == 0xff {
-            panic!("Cannot pull from an empty stack");
+            self.fault(EmulatorErrorKind::StackUnderflow)?;
         }
-        self.get_regs_mut().sp += 1;
+        let regs = self.get_regs_mut();
+        regs.sp = regs.sp.wrapping_add(1);
         let byte = self.read_from_stack();
-        byte
+        let address = self.get_regs().sp as u16 + 0x100;
+        self.observers.on_stack_pull(address, byte);
+        Ok(byte)
+    }
+
+    fn push_pc(&mut self, pc: u16) -> Result<(), EmulatorError> {
+        self.push((pc >> 8) as u8)?;
+        self.push((pc & 0xff) as u8)
     }
 
-    fn push_pc(&mut self, offset: u16) {
-        let pc = self.get_regs().pc + offset;
-        self.push((pc >> 8) as u8);
-        self.push((pc & 0xff) as u8);
+    fn pull_pc(&mut self) -> Result<u16, EmulatorError> {
+        let mut pc = self.pull()? as u16;
+        pc |= (self.pull()? as u16) << 8;
+        Ok(pc)
     }
 
-    fn pull_pc(&mut self, offset: u16) -> u16 {
-        let mut pc = self.pull() as u16;
-        pc |= (self.pull() as u16) << 8;
-        pc += offset;
-        pc
+    // BREAK and UNUSED only exist in the copy of the flags pushed to the stack
+    fn push_flags(&mut self, is_break: bool) -> Result<(), EmulatorError> {
+        let mut flags = self.get_regs().flags | CpuFlags::UNUSED;
+        flags.set(CpuFlags::BREAK, is_break);
+        self.push(flags.bits())
     }
 
-    fn push_flags(&mut self) {
-        self.get_regs_mut().flags.insert(CpuFlags::BREAK);
-        let flags = self.get_regs().flags.bits();
-        self.push(flags);
+    fn pull_flags(&mut self) -> Result<(), EmulatorError> {
+        let flags = CpuFlags::from_bits_retain(self.pull()?);
+        self.get_regs_mut().flags = flags.difference(CpuFlags::BREAK | CpuFlags::UNUSED);
+        Ok(())
     }
 
-    fn pull_flags(&mut self) {
-        let contains_break = self.get_regs().flags.contains(CpuFlags::BREAK);
-        self.get_regs_mut().flags = CpuFlags::from_bits(self.pull()).unwrap();
-        if contains_break {
-            self.get_regs_mut().flags.insert(CpuFlags::BREAK);
+    fn poll_interrupts(&mut self) -> Result<Option<InterruptKind>, EmulatorError> {
+        let kind = if self.nmi_pending {
+            self.nmi_pending = false;
+            InterruptKind::Nmi
+        } else if self.irq_asserted && !self.get_regs().flags.contains(CpuFlags::INT_DISABLE) {
+            InterruptKind::Irq
         } else {
-            self.get_regs_mut().flags.remove(CpuFlags::BREAK);
+            return Ok(None);
+        };
+        let pc = self.get_regs().pc;
+        self.current_pc = pc;
+        self.current_opcode = None;
+        self.service_interrupt(kind)?;
+        Ok(Some(kind))
+    }
+
+    fn service_interrupt(&mut self, kind: InterruptKind) -> Result<(), EmulatorError> {
+        let pc = self.get_regs().pc;
+        self.push_pc(pc)?;
+        self.push_flags(kind == InterruptKind::Brk)?;
+        self.get_regs_mut().flags.insert(CpuFlags::INT_DISABLE);
+        let vector_addr = match kind {
+            InterruptKind::Reset => self.get_reset_addr(),
+            InterruptKind::Nmi => self.get_nmi_addr(),
+            InterruptKind::Irq | InterruptKind::Brk => self.get_irq_addr(),
+        };
+        self.set_pc(vector_addr);
+        let sp = self.get_regs().sp;
+        self.push_call_frame(CallFrame {
+            kind: FrameKind::Interrupt(kind),
+            entry: vector_addr,
+            call_site: self.current_pc,
+            return_addr: pc,
+            sp,
+        });
+        self.observers.on_interrupt(kind);
+        Ok(())
+    }
+
+    // Call stack changes are recorded instead of the whole call stack, which can be deep
+    fn push_call_frame(&mut self, frame: CallFrame) {
+        let dropped = self.call_stack.push(frame);
+        if let Some(history) = &mut self.history {
+            history.record_call_stack_change(CallStackChange::Pushed(dropped));
         }
     }
 
-    fn interrupt(&mut self) {
-        self.stop_signalled = true;
-        let irq_addr = self.get_irq_addr();
-        self.set_pc(irq_addr);
+    fn pop_call_frames(&mut self, target: u16) {
+        let popped = self.call_stack.pop(self.current_pc, target);
+        if let Some(history) = &mut self.history {
+            history.record_call_stack_change(CallStackChange::Popped(popped.collect()));
+        }
     }
 
     fn add(&mut self, a: u8, b: u8) -> u8 {
-        let mut result = a;
-        if (result as usize + b as usize + self.carry() as usize) > 0xff {
-            self.get_regs_mut().flags.insert(CpuFlags::CARRY);
-            self.get_regs_mut().flags.insert(CpuFlags::OVERFLOW);
-            result += b + self.carry() - 0xff;
-        } else {
-            result += b + self.carry();
-        }
+        let sum = a as u16 + b as u16 + self.carry() as u16;
+        let result = sum as u8;
 
+        self.get_regs_mut().flags.set(CpuFlags::CARRY, sum > 0xff);
+        // Signed overflow: both operands share a sign that differs from the result's
+        self.get_regs_mut()
+            .flags
+            .set(CpuFlags::OVERFLOW, (a ^ result) & (b ^ result) & 0x80 != 0);
         self.set_zero_or_neg(result);
 
         result
     }
 
+    // The carry is an inverted borrow, so a - b - !c == a + !b + c
     fn sub(&mut self, a: u8, b: u8) -> u8 {
-        let mut result = a;
-        if (result as isize - b as isize - self.carry() as isize) < 0 {
-            self.get_regs_mut().flags.insert(CpuFlags::CARRY);
-            result = 0xff - (result + b + self.carry());
-        } else {
-            result -= b + self.carry();
+        self.add(a, !b)
+    }
+
+    fn compare(&mut self, register: u8, byte: u8) {
+        self.get_regs_mut()
+            .flags
+            .set(CpuFlags::CARRY, register >= byte);
+        self.set_zero_or_neg(register.wrapping_sub(byte));
+    }
+
+    fn is_decimal(&self) -> bool {
+        self.variant.has_decimal_mode() && self.get_regs().flags.contains(CpuFlags::DEC_MODE)
+    }
+
+    // NMOS decimal addition: N and V come from the intermediate result before the high nibble is
+    // adjusted and Z from the binary sum, which is what real hardware does for invalid BCD too.
+    fn add_decimal(&mut self, a: u8, b: u8) -> u8 {
+        let carry = self.carry() as u16;
+        let binary = (a as u16 + b as u16 + carry) as u8;
+
+        let mut low = (a & 0x0f) as u16 + (b & 0x0f) as u16 + carry;
+        if low >= 0x0a {
+            low = ((low + 0x06) & 0x0f) + 0x10;
         }
+        let mut result = (a & 0xf0) as u16 + (b & 0xf0) as u16 + low;
+        let signed = (a & 0xf0) as i8 as i16 + (b & 0xf0) as i8 as i16 + low as i16;
 
-        self.set_zero_or_neg(result);
+        let regs = self.get_regs_mut();
+        regs.flags.set(CpuFlags::ZERO, binary == 0);
+        regs.flags.set(CpuFlags::NEG, result & 0x80 != 0);
+        regs.flags
+            .set(CpuFlags::OVERFLOW, !(-128..=127).contains(&signed));
 
-        result
+        if result >= 0xa0 {
+            result += 0x60;
+        }
+        regs.flags.set(CpuFlags::CARRY, result >= 0x100);
+
+        result as u8
+    }
+
+    // NMOS decimal subtraction: every flag is set exactly as in binary mode, only the accumulator
+    // is adjusted.
+    fn sub_decimal(&mut self, a: u8, b: u8) -> u8 {
+        let borrow = 1 - self.carry() as i16;
+        _ = self.sub(a, b);
+
+        let mut low = (a & 0x0f) as i16 - (b & 0x0f) as i16 - borrow;
+        if low < 0 {
+            low = ((low - 0x06) & 0x0f) - 0x10;
+        }
+        let mut result = (a & 0xf0) as i16 - (b & 0xf0) as i16 + low;
+        if result < 0 {
+            result -= 0x60;
+        }
+
+        result as u8
     }
 
     fn shl(&mut self, a: u8) -> u8 {
         let result = a << 1;
-        if a >> 7 == 1 {
-            self.get_regs_mut().flags.insert(CpuFlags::CARRY);
-        } else {
-            self.get_regs_mut().flags.remove(CpuFlags::CARRY);
-        }
-        self.set_zero_or_neg(a);
+        self.get_regs_mut()
+            .flags
+            .set(CpuFlags::CARRY, a & 0x80 != 0);
+        self.set_zero_or_neg(result);
         result
     }
 
     fn shr(&mut self, a: u8) -> u8 {
         let result = a >> 1;
-        if a & 1 == 1 {
-            self.get_regs_mut().flags.insert(CpuFlags::CARRY);
-        } else {
-            self.get_regs_mut().flags.remove(CpuFlags::CARRY);
-        }
-        self.set_zero_or_neg(a);
+        self.get_regs_mut().flags.set(CpuFlags::CARRY, a & 1 != 0);
+        self.set_zero_or_neg(result);
         result
     }
 
     fn rol(&mut self, a: u8) -> u8 {
-        let mut result = self.shl(a);
-        if self.get_regs().flags.contains(CpuFlags::CARRY) {
-            result |= 1;
-        }
+        let result = (a << 1) | self.carry();
+        self.get_regs_mut()
+            .flags
+            .set(CpuFlags::CARRY, a & 0x80 != 0);
         self.set_zero_or_neg(result);
         result
     }
 
     fn ror(&mut self, a: u8) -> u8 {
-        let mut result = self.shr(a);
-        if self.get_regs().flags.contains(CpuFlags::CARRY) {
-            result |= 1 << 7;
-        }
+        let result = (a >> 1) | (self.carry() << 7);
+        self.get_regs_mut().flags.set(CpuFlags::CARRY, a & 1 != 0);
         self.set_zero_or_neg(result);
         result
     }
 
     fn set_zero_or_neg(&mut self, result: u8) {
-        if result == 0 {
-            self.get_regs_mut().flags.insert(CpuFlags::ZERO);
-        } else {
-            self.get_regs_mut().flags.remove(CpuFlags::ZERO);
-        }
-        if self.get_regs().x >= 0b1000_0000 {
-            self.get_regs_mut().flags.insert(CpuFlags::NEG);
-        } else {
-            self.get_regs_mut().flags.remove(CpuFlags::NEG);
-        }
+        self.get_regs_mut().flags.set(CpuFlags::ZERO, result == 0);
+        self.get_regs_mut()
+            .flags
+            .set(CpuFlags::NEG, result & 0x80 != 0);
     }
 
     fn carry(&self) -> u8 {
@@ -306,39 +1096,161 @@ impl Emulator {
         }
     }
 
-    fn execute_next(&mut self) {
-        let instruction = self.decode_next();
-        self.execute(instruction);
+    fn page_crossing_penalty(&self, ins: &Instruction, address: Option<u16>) -> u8 {
+        let index = match ins.addressing_mode {
+            AddressingMode::AbsoluteX => self.get_regs().x,
+            AddressingMode::AbsoluteY | AddressingMode::IndirectY => self.get_regs().y,
+            _ => return 0,
+        };
+        let Some(address) = address else {
+            return 0;
+        };
+        let base = address.wrapping_sub(index as u16);
+        if base & 0xff00 != address & 0xff00 {
+            1
+        } else {
+            0
+        }
     }
 
-    fn execute(&mut self, ins: Instruction) {
+    // Returns the cycles a taken branch adds, or `None` if the branch is not taken
+    fn branch(
+        &mut self,
+        ins: &Instruction,
+        address: Option<u16>,
+        condition: bool,
+    ) -> Result<Option<u8>, EmulatorError> {
+        if !condition {
+            return Ok(None);
+        }
+        let pc = self.get_regs().pc;
+        let addr = self.get_target_address(ins, address)?;
+        self.set_pc(addr);
+        Ok(Some(if pc & 0xff00 != addr & 0xff00 { 2 } else { 1 }))
+    }
+
+    fn execute_next(&mut self) -> Result<StepResult, EmulatorError> {
+        if let Some(history) = &mut self.history {
+            history.push(HistoryEntry {
+                regs: self.regs,
+                cycles: self.cycles,
+                nmi_pending: self.nmi_pending,
+                stop_signalled: self.stop_signalled,
+                call_stack_changes: Vec::new(),
+                writes: Vec::new(),
+            });
+        }
+        self.stop_signalled = false;
+        self.watchpoint_hit = None;
+        let interrupt = self.poll_interrupts()?;
+        let mut cycles = if interrupt.is_some() {
+            INTERRUPT_CYCLES
+        } else {
+            0
+        };
+
+        let pc = self.get_regs().pc;
+        if self.tracer.is_some() {
+            let line = self.get_trace_line_at(pc, self.cycles + cycles as u64);
+            if let Some(tracer) = &mut self.tracer {
+                tracer.trace(&line);
+            }
+        }
+        self.current_pc = pc;
+        let mut result = StepResult {
+            address: pc,
+            instruction: None,
+            effective_address: None,
+            cycles: 0,
+            interrupt,
+            halted: false,
+            watchpoint: None,
+            branch_taken: None,
+        };
+        match self.decode_next() {
+            Ok(instruction) => {
+                if !self.observers.is_empty() {
+                    let mut regs = *self.get_regs();
+                    regs.pc = pc;
+                    self.observers.on_instruction(&instruction, &regs);
+                }
+                let address = self.get_operand_address(&instruction);
+                cycles += self.execute(&instruction, address, &mut result)?;
+                result.instruction = Some(instruction);
+                result.effective_address = address;
+            }
+            // The emulator reads operands from the bus, so they are never truncated
+            Err(DecodeError::UnknownOpCode(_) | DecodeError::Truncated(_)) => {
+                // Carrying on treats the opcode as a one byte nop
+                self.fault(EmulatorErrorKind::IllegalOpcode)?;
+                cycles += ILLEGAL_OPCODE_CYCLES;
+            }
+        }
+
+        if self.stop_signalled {
+            result.halted = true;
+            result.interrupt = result.interrupt.or(Some(InterruptKind::Brk));
+        }
+        self.cycles += cycles as u64;
+        result.cycles = cycles;
+        result.watchpoint = self.watchpoint_hit.take();
+        if !self.observers.is_empty() {
+            let regs = *self.get_regs();
+            self.observers.on_step(&result, &regs);
+        }
+        Ok(result)
+    }
+
+    // Records whether a branch was taken in `result`
+    fn execute(
+        &mut self,
+        ins: &Instruction,
+        address: Option<u16>,
+        result: &mut StepResult,
+    ) -> Result<u8, EmulatorError> {
+        let mut cycles = ins.cycles;
+        if matches!(
+            ins.name,
+            InstructionName::lda
+                | InstructionName::ldx
+                | InstructionName::ldy
+                | InstructionName::and
+                | InstructionName::eor
+                | InstructionName::ora
+                | InstructionName::adc
+                | InstructionName::sbc
+                | InstructionName::cmp
+        ) {
+            cycles += self.page_crossing_penalty(ins, address);
+        }
+
         match ins.name {
             InstructionName::lda => {
-                self.get_regs_mut().a = self.read_byte(ins.addressing_mode, ins.operand);
+                self.get_regs_mut().a = self.read_operand(ins, address);
                 let a = self.get_regs().a;
                 self.set_zero_or_neg(a);
             }
             InstructionName::ldx => {
-                self.get_regs_mut().x = self.read_byte(ins.addressing_mode, ins.operand);
+                self.get_regs_mut().x = self.read_operand(ins, address);
                 let x = self.get_regs().x;
                 self.set_zero_or_neg(x);
             }
             InstructionName::ldy => {
-                self.get_regs_mut().y = self.read_byte(ins.addressing_mode, ins.operand);
+                self.get_regs_mut().y = self.read_operand(ins, address);
                 let y = self.get_regs().y;
                 self.set_zero_or_neg(y);
             }
             InstructionName::sta => {
                 let a = self.get_regs().a;
-                self.write_byte(ins.addressing_mode, ins.operand, a);
+                self.write_operand(ins, address, a);
             }
             InstructionName::stx => {
                 let x = self.get_regs().x;
-                self.write_byte(ins.addressing_mode, ins.operand, x);
+                self.write_operand(ins, address, x);
             }
             InstructionName::sty => {
                 let y = self.get_regs().y;
-                self.write_byte(ins.addressing_mode, ins.operand, y);
+                self.write_operand(ins, address, y);
             }
 
             InstructionName::tax => {
@@ -370,214 +1282,210 @@ impl Emulator {
             InstructionName::txs => {
                 let x = self.get_regs().x;
                 self.get_regs_mut().sp = x;
-                self.set_zero_or_neg(x);
+                let popped = self.call_stack.set_stack_pointer(self.current_pc, x);
+                if let Some(history) = &mut self.history {
+                    history.record_call_stack_change(CallStackChange::Popped(popped.collect()));
+                }
             }
             InstructionName::pha => {
                 let a = self.get_regs().a;
-                self.push(a);
+                self.push(a)?;
             }
             InstructionName::php => {
-                self.push_flags();
+                self.push_flags(true)?;
             }
             InstructionName::pla => {
-                self.get_regs_mut().a = self.pull();
+                self.get_regs_mut().a = self.pull()?;
                 let a = self.get_regs().a;
                 self.set_zero_or_neg(a);
             }
-            InstructionName::plp => self.pull_flags(),
+            InstructionName::plp => self.pull_flags()?,
 
             InstructionName::and => {
-                let result = self.get_regs().a & self.read_byte(ins.addressing_mode, ins.operand);
+                let byte = self.read_operand(ins, address);
+                let result = self.get_regs().a & byte;
                 self.get_regs_mut().a = result;
                 self.set_zero_or_neg(result);
             }
             InstructionName::eor => {
-                let result = self.get_regs().a ^ self.read_byte(ins.addressing_mode, ins.operand);
+                let byte = self.read_operand(ins, address);
+                let result = self.get_regs().a ^ byte;
                 self.get_regs_mut().a = result;
                 self.set_zero_or_neg(result);
             }
             InstructionName::ora => {
-                let result = self.get_regs().a | self.read_byte(ins.addressing_mode, ins.operand);
+                let byte = self.read_operand(ins, address);
+                let result = self.get_regs().a | byte;
                 self.get_regs_mut().a = result;
                 self.set_zero_or_neg(result);
             }
             InstructionName::bit => {
-                let byte = self.read_byte(ins.addressing_mode, ins.operand);
-                if byte >> 7 == 1 {
-                    self.get_regs_mut().flags.insert(CpuFlags::NEG);
-                }
-                if (byte >> 6) & 1 == 1 {
-                    self.get_regs_mut().flags.insert(CpuFlags::OVERFLOW);
-                }
+                let byte = self.read_operand(ins, address);
                 let and = self.get_regs().a & byte;
-                if and == 0 {
-                    self.get_regs_mut().flags.insert(CpuFlags::ZERO);
-                } else {
-                    self.get_regs_mut().flags.remove(CpuFlags::ZERO);
-                }
+                let regs = self.get_regs_mut();
+                regs.flags.set(CpuFlags::NEG, byte & 0x80 != 0);
+                regs.flags.set(CpuFlags::OVERFLOW, byte & 0x40 != 0);
+                regs.flags.set(CpuFlags::ZERO, and == 0);
             }
 
             InstructionName::adc => {
-                let byte = self.read_byte(ins.addressing_mode, ins.operand);
+                let byte = self.read_operand(ins, address);
                 let a = self.get_regs().a;
-                self.get_regs_mut().a = self.add(a, byte);
+                self.get_regs_mut().a = if self.is_decimal() {
+                    self.add_decimal(a, byte)
+                } else {
+                    self.add(a, byte)
+                };
             }
             InstructionName::sbc => {
-                let byte = self.read_byte(ins.addressing_mode, ins.operand);
+                let byte = self.read_operand(ins, address);
                 let a = self.get_regs().a;
-                self.get_regs_mut().a = self.sub(a, byte);
+                self.get_regs_mut().a = if self.is_decimal() {
+                    self.sub_decimal(a, byte)
+                } else {
+                    self.sub(a, byte)
+                };
             }
             InstructionName::cmp => {
-                let byte = self.read_byte(ins.addressing_mode, ins.operand);
+                let byte = self.read_operand(ins, address);
                 let a = self.get_regs().a;
-                _ = self.sub(a, byte);
+                self.compare(a, byte);
             }
             InstructionName::cpx => {
-                let byte = self.read_byte(ins.addressing_mode, ins.operand);
+                let byte = self.read_operand(ins, address);
                 let x = self.get_regs().x;
-                _ = self.sub(x, byte);
+                self.compare(x, byte);
             }
             InstructionName::cpy => {
-                let byte = self.read_byte(ins.addressing_mode, ins.operand);
+                let byte = self.read_operand(ins, address);
                 let y = self.get_regs().y;
-                _ = self.sub(y, byte);
+                self.compare(y, byte);
             }
 
             InstructionName::inc => {
-                let mut byte = self.read_byte(ins.addressing_mode, ins.operand);
-                byte = self.add(byte, 1);
-                self.write_byte(ins.addressing_mode, ins.operand, byte);
+                let byte = self.read_operand(ins, address).wrapping_add(1);
+                self.write_operand(ins, address, byte);
+                self.set_zero_or_neg(byte);
             }
             InstructionName::inx => {
-                let x = self.get_regs().x;
-                self.get_regs_mut().x = self.add(x, 1);
+                let x = self.get_regs().x.wrapping_add(1);
+                self.get_regs_mut().x = x;
+                self.set_zero_or_neg(x);
             }
             InstructionName::iny => {
-                let y = self.get_regs().y;
-                self.get_regs_mut().y = self.add(y, 1);
+                let y = self.get_regs().y.wrapping_add(1);
+                self.get_regs_mut().y = y;
+                self.set_zero_or_neg(y);
             }
             InstructionName::dec => {
-                let mut byte = self.read_byte(ins.addressing_mode, ins.operand);
-                let has_carry = self.get_regs().flags.contains(CpuFlags::CARRY);
-                byte = self.sub(byte, 1);
-                self.write_byte(ins.addressing_mode, ins.operand, byte);
-                if has_carry {
-                    self.get_regs_mut().flags.insert(CpuFlags::CARRY);
-                } else {
-                    self.get_regs_mut().flags.remove(CpuFlags::CARRY);
-                }
+                let byte = self.read_operand(ins, address).wrapping_sub(1);
+                self.write_operand(ins, address, byte);
+                self.set_zero_or_neg(byte);
             }
             InstructionName::dex => {
-                let has_carry = self.get_regs().flags.contains(CpuFlags::CARRY);
-                let x = self.get_regs().x;
-                self.get_regs_mut().x = self.sub(x, 1);
-                if has_carry {
-                    self.get_regs_mut().flags.insert(CpuFlags::CARRY);
-                } else {
-                    self.get_regs_mut().flags.remove(CpuFlags::CARRY);
-                }
+                let x = self.get_regs().x.wrapping_sub(1);
+                self.get_regs_mut().x = x;
+                self.set_zero_or_neg(x);
             }
             InstructionName::dey => {
-                let has_carry = self.get_regs().flags.contains(CpuFlags::CARRY);
-                let y = self.get_regs().y;
-                self.get_regs_mut().y = self.sub(y, 1);
-                if has_carry {
-                    self.get_regs_mut().flags.insert(CpuFlags::CARRY);
-                } else {
-                    self.get_regs_mut().flags.remove(CpuFlags::CARRY);
-                }
+                let y = self.get_regs().y.wrapping_sub(1);
+                self.get_regs_mut().y = y;
+                self.set_zero_or_neg(y);
             }
 
             InstructionName::asl => {
-                let mut byte = self.read_byte(ins.addressing_mode, ins.operand);
+                let mut byte = self.read_operand(ins, address);
                 byte = self.shl(byte);
-                self.write_byte(ins.addressing_mode, ins.operand, byte);
+                self.write_operand(ins, address, byte);
             }
             InstructionName::lsr => {
-                let mut byte = self.read_byte(ins.addressing_mode, ins.operand);
+                let mut byte = self.read_operand(ins, address);
                 byte = self.shr(byte);
-                self.write_byte(ins.addressing_mode, ins.operand, byte);
+                self.write_operand(ins, address, byte);
             }
             InstructionName::rol => {
-                let mut byte = self.read_byte(ins.addressing_mode, ins.operand);
+                let mut byte = self.read_operand(ins, address);
                 byte = self.rol(byte);
-                self.write_byte(ins.addressing_mode, ins.operand, byte);
+                self.write_operand(ins, address, byte);
             }
             InstructionName::ror => {
-                let mut byte = self.read_byte(ins.addressing_mode, ins.operand);
+                let mut byte = self.read_operand(ins, address);
                 byte = self.ror(byte);
-                self.write_byte(ins.addressing_mode, ins.operand, byte);
+                self.write_operand(ins, address, byte);
             }
 
             InstructionName::jmp => {
-                let addr = self.get_absolute_address(ins.addressing_mode, ins.operand);
+                let addr = self.get_target_address(ins, address)?;
                 self.set_pc(addr);
             }
             InstructionName::jsr => {
-                let addr = self.get_absolute_address(ins.addressing_mode, ins.operand);
-                self.push_pc(0);
+                let addr = self.get_target_address(ins, address)?;
+                // The return address pushed is the last byte of the jsr, rts adds one back
+                let return_addr = self.get_regs().pc.wrapping_sub(1);
+                self.push_pc(return_addr)?;
                 self.set_pc(addr);
+                let sp = self.get_regs().sp;
+                self.push_call_frame(CallFrame {
+                    kind: FrameKind::Subroutine,
+                    entry: addr,
+                    call_site: self.current_pc,
+                    return_addr: return_addr.wrapping_add(1),
+                    sp,
+                });
             }
             InstructionName::rts => {
-                let pc = self.pull_pc(0);
+                let pc = self.pull_pc()?.wrapping_add(1);
                 self.set_pc(pc);
+                self.pop_call_frames(pc);
             }
 
             InstructionName::bcc => {
-                if self.get_regs().flags.contains(CpuFlags::CARRY) {
-                    return;
-                }
-                let addr = self.get_absolute_address(ins.addressing_mode, ins.operand);
-                self.set_pc(addr);
+                let flags = self.get_regs().flags;
+                let extra = self.branch(ins, address, !flags.contains(CpuFlags::CARRY))?;
+                result.branch_taken = Some(extra.is_some());
+                cycles += extra.unwrap_or(0);
             }
             InstructionName::bcs => {
-                if !self.get_regs().flags.contains(CpuFlags::CARRY) {
-                    return;
-                }
-                let addr = self.get_absolute_address(ins.addressing_mode, ins.operand);
-                self.set_pc(addr);
+                let flags = self.get_regs().flags;
+                let extra = self.branch(ins, address, flags.contains(CpuFlags::CARRY))?;
+                result.branch_taken = Some(extra.is_some());
+                cycles += extra.unwrap_or(0);
             }
             InstructionName::beq => {
-                if !self.get_regs().flags.contains(CpuFlags::ZERO) {
-                    return;
-                }
-                let addr = self.get_absolute_address(ins.addressing_mode, ins.operand);
-                self.set_pc(addr);
+                let flags = self.get_regs().flags;
+                let extra = self.branch(ins, address, flags.contains(CpuFlags::ZERO))?;
+                result.branch_taken = Some(extra.is_some());
+                cycles += extra.unwrap_or(0);
             }
             InstructionName::bmi => {
-                if !self.get_regs().flags.contains(CpuFlags::NEG) {
-                    return;
-                }
-                let addr = self.get_absolute_address(ins.addressing_mode, ins.operand);
-                self.set_pc(addr);
+                let flags = self.get_regs().flags;
+                let extra = self.branch(ins, address, flags.contains(CpuFlags::NEG))?;
+                result.branch_taken = Some(extra.is_some());
+                cycles += extra.unwrap_or(0);
             }
             InstructionName::bne => {
-                if self.get_regs().flags.contains(CpuFlags::ZERO) {
-                    return;
-                }
-                let addr = self.get_absolute_address(ins.addressing_mode, ins.operand);
-                self.set_pc(addr);
+                let flags = self.get_regs().flags;
+                let extra = self.branch(ins, address, !flags.contains(CpuFlags::ZERO))?;
+                result.branch_taken = Some(extra.is_some());
+                cycles += extra.unwrap_or(0);
             }
             InstructionName::bpl => {
-                if !self.get_regs().flags.contains(CpuFlags::NEG) {
-                    return;
-                }
-                let addr = self.get_absolute_address(ins.addressing_mode, ins.operand);
-                self.set_pc(addr);
+                let flags = self.get_regs().flags;
+                let extra = self.branch(ins, address, !flags.contains(CpuFlags::NEG))?;
+                result.branch_taken = Some(extra.is_some());
+                cycles += extra.unwrap_or(0);
             }
             InstructionName::bvc => {
-                if self.get_regs().flags.contains(CpuFlags::OVERFLOW) {
-                    return;
-                }
-                let addr = self.get_absolute_address(ins.addressing_mode, ins.operand);
-                self.set_pc(addr);
+                let flags = self.get_regs().flags;
+                let extra = self.branch(ins, address, !flags.contains(CpuFlags::OVERFLOW))?;
+                result.branch_taken = Some(extra.is_some());
+                cycles += extra.unwrap_or(0);
             }
             InstructionName::bvs => {
-                if !self.get_regs().flags.contains(CpuFlags::OVERFLOW) {
-                    return;
-                }
-                let addr = self.get_absolute_address(ins.addressing_mode, ins.operand);
-                self.set_pc(addr);
+                let flags = self.get_regs().flags;
+                let extra = self.branch(ins, address, flags.contains(CpuFlags::OVERFLOW))?;
+                result.branch_taken = Some(extra.is_some());
+                cycles += extra.unwrap_or(0);
             }
 
             InstructionName::clc => {
@@ -603,21 +1511,543 @@ impl Emulator {
             }
 
             InstructionName::brk => {
-                if !self.get_regs().flags.contains(CpuFlags::INT_DISABLE) {
-                    self.interrupt();
-                    let ret_addr = self.get_regs().pc + 2;
-                    self.push((ret_addr >> 8) as u8);
-                    self.push((ret_addr & 0xff) as u8);
-                    let flags = self.get_regs().flags.bits();
-                    self.push(flags);
-                }
+                // The byte after the opcode is padding, so the return address skips it
+                let pc = self.get_regs().pc.wrapping_add(1);
+                self.set_pc(pc);
+                self.service_interrupt(InterruptKind::Brk)?;
+                self.stop_signalled = true;
             }
             InstructionName::nop => {}
             InstructionName::rti => {
-                self.pull_flags();
-                let pc = self.pull_pc(0);
+                self.pull_flags()?;
+                let pc = self.pull_pc()?;
                 self.set_pc(pc);
+                self.pop_call_frames(pc);
             }
         }
+
+        Ok(cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::breakpoint::BreakpointKind;
+    use crate::callstack::MAX_CALL_STACK_DEPTH;
+    use crate::mem::Memory;
+
+    // Assembles `source` at $0200, followed by a `brk`, and points the program counter at it
+    fn load_program(source: &str) -> Emulator<Memory> {
+        let image = assemble(&format!(".org $0200\n{source}\nbrk\n")).unwrap();
+        let mut emulator = Emulator::new(Memory::new_from_bytes(image));
+        emulator.get_regs_mut().pc = 0x0200;
+        emulator
+    }
+
+    fn run_program(variant: CpuVariant, source: &str) -> Emulator<Memory> {
+        let mut emulator = load_program(source);
+        emulator.set_variant(variant);
+        while !emulator.step().halted {}
+        emulator
+    }
+
+    fn run_nmos(source: &str) -> (u8, CpuFlags) {
+        let emulator = run_program(CpuVariant::Nmos6502, source);
+        let regs = emulator.get_regs();
+        (regs.a, regs.flags)
+    }
+
+    #[test]
+    fn page_crossings_and_taken_branches_cost_extra_cycles() {
+        let source = "
+            .org $0200
+            ldx #$01
+            lda $02ff,x
+            lda $0200,x
+            sta $02ff,x
+            bne skip
+            skip: beq skip
+            brk
+            .org $fffc
+            .word $0200";
+        let image = assemble(source).unwrap();
+        let mut emulator = Emulator::new(Memory::new_from_bytes(image));
+        emulator.run_until_break();
+        // ldx 2, lda 4 + 1 for crossing a page, lda 4, sta 5 whether or not it crosses one,
+        // bne 2 + 1 for being taken, beq 2 and brk 7
+        assert_eq!(emulator.get_cycles(), 28);
+    }
+
+    #[test]
+    fn decimal_mode_is_ignored_by_the_2a03() {
+        let emulator = run_program(CpuVariant::Ricoh2A03, "sed\nclc\nlda #$09\nadc #$01");
+        assert_eq!(emulator.get_regs().a, 0x0a);
+        let emulator = run_program(CpuVariant::Ricoh2A03, "sed\nsec\nlda #$10\nsbc #$01");
+        assert_eq!(emulator.get_regs().a, 0x0f);
+        // The flag itself can still be set, only the arithmetic ignores it
+        assert!(emulator.get_regs().flags.contains(CpuFlags::DEC_MODE));
+    }
+
+    #[test]
+    fn decimal_mode_is_honored_by_the_nmos_6502() {
+        let emulator = run_program(CpuVariant::Nmos6502, "sed\nclc\nlda #$09\nadc #$01");
+        assert_eq!(emulator.get_regs().a, 0x10);
+        let emulator = run_program(CpuVariant::Nmos6502, "sed\nsec\nlda #$10\nsbc #$01");
+        assert_eq!(emulator.get_regs().a, 0x09);
+    }
+
+    #[test]
+    fn adc_overflows_into_the_sign_bit() {
+        let (a, flags) = run_nmos("clc\nlda #$50\nadc #$50");
+        assert_eq!(a, 0xa0);
+        assert!(flags.contains(CpuFlags::NEG));
+        assert!(flags.contains(CpuFlags::OVERFLOW));
+        assert!(!flags.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn sbc_of_a_negative_number_overflows() {
+        let (a, flags) = run_nmos("sec\nlda #$50\nsbc #$b0");
+        assert_eq!(a, 0xa0);
+        assert!(flags.contains(CpuFlags::OVERFLOW));
+        assert!(!flags.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn decimal_adc_carries_out_of_the_high_digit() {
+        let (a, flags) = run_nmos("sed\nclc\nlda #$58\nadc #$46");
+        assert_eq!(a, 0x04);
+        assert!(flags.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn decimal_sbc_borrows_into_the_high_digit() {
+        let (a, flags) = run_nmos("sed\nsec\nlda #$12\nsbc #$21");
+        assert_eq!(a, 0x91);
+        assert!(!flags.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn decimal_adc_takes_zero_from_the_binary_sum() {
+        // The binary sum is $9a, so Z stays clear even though the accumulator ends up zero
+        let (a, flags) = run_nmos("sed\nclc\nlda #$99\nadc #$01");
+        assert_eq!(a, 0x00);
+        assert!(!flags.contains(CpuFlags::ZERO));
+        assert!(flags.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn increments_and_decrements_leave_carry_and_overflow_alone() {
+        let instructions = ["inc $10", "dec $10", "inx", "iny", "dex", "dey"];
+        for instruction in instructions {
+            for status in [0x00, 0x41] {
+                // Pulling the status is the only way to set V directly
+                let source = format!("lda #${status:02x}\npha\nplp\n{instruction}");
+                let (_, flags) = run_nmos(&source);
+                let expected = CpuFlags::from_bits_retain(status);
+                assert_eq!(
+                    flags & (CpuFlags::CARRY | CpuFlags::OVERFLOW),
+                    expected,
+                    "{instruction} with status {status:#04x}"
+                );
+            }
+        }
+    }
+
+    // Points the vector at `vector_addr` to `target`
+    fn set_vector(emulator: &mut Emulator<Memory>, vector_addr: u16, target: u16) {
+        let bus = emulator.get_bus_mut();
+        bus.write(vector_addr, target as u8);
+        bus.write(vector_addr + 1, (target >> 8) as u8);
+    }
+
+    #[test]
+    fn irq_is_masked_by_int_disable() {
+        let mut emulator = load_program("nop\nnop");
+        set_vector(&mut emulator, IRQ_VEC_LOW_ADDR, 0x0300);
+        emulator.get_bus_mut().write(0x0300, 0xea);
+        emulator.get_regs_mut().flags.insert(CpuFlags::INT_DISABLE);
+        emulator.assert_irq();
+        let result = emulator.step();
+        assert_eq!(result.interrupt, None);
+        assert_eq!(emulator.get_regs().pc, 0x0201);
+
+        emulator.get_regs_mut().flags.remove(CpuFlags::INT_DISABLE);
+        let result = emulator.step();
+        assert_eq!(result.interrupt, Some(InterruptKind::Irq));
+        assert_eq!(result.address, 0x0300);
+        // Taking the interrupt costs 7 cycles on top of the handler's first nop
+        assert_eq!(result.cycles, 9);
+        assert_eq!(emulator.get_regs().pc, 0x0301);
+        assert!(emulator.get_regs().flags.contains(CpuFlags::INT_DISABLE));
+    }
+
+    #[test]
+    fn interrupts_push_the_return_address_and_the_flags_without_break() {
+        let mut emulator = load_program("sec\nnop");
+        set_vector(&mut emulator, IRQ_VEC_LOW_ADDR, 0x0300);
+        emulator.get_bus_mut().write(0x0300, 0xea);
+        emulator.step();
+        emulator.assert_irq();
+        emulator.step();
+        let bus = emulator.get_bus();
+        assert_eq!(emulator.get_regs().sp, 0xfc);
+        assert_eq!((bus.read(0x01ff), bus.read(0x01fe)), (0x02, 0x01));
+        let pushed = CpuFlags::from_bits_retain(bus.read(0x01fd));
+        assert_eq!(pushed, CpuFlags::CARRY | CpuFlags::UNUSED);
+    }
+
+    #[test]
+    fn nmi_is_taken_once_per_edge_regardless_of_int_disable() {
+        let mut emulator = load_program("nop\nnop");
+        set_vector(&mut emulator, NMI_VEC_LOW_ADDR, 0x0300);
+        emulator.get_bus_mut().write(0x0300, 0xea);
+        emulator.get_bus_mut().write(0x0301, 0xea);
+        emulator.get_regs_mut().flags.insert(CpuFlags::INT_DISABLE);
+        // Edges before the interrupt is taken are merged into one
+        emulator.trigger_nmi();
+        emulator.trigger_nmi();
+        let result = emulator.step();
+        assert_eq!(result.interrupt, Some(InterruptKind::Nmi));
+        assert_eq!(result.address, 0x0300);
+        assert!(!emulator.is_nmi_pending());
+
+        let result = emulator.step();
+        assert_eq!(result.interrupt, None);
+        assert_eq!(result.address, 0x0301);
+    }
+
+    #[test]
+    fn reset_loads_the_reset_vector() {
+        let mut emulator = load_program("nop");
+        set_vector(&mut emulator, RESET_VEC_LOW_ADDR, 0x0400);
+        let cycles = emulator.get_cycles();
+        emulator.reset();
+        let regs = emulator.get_regs();
+        assert_eq!(regs.pc, 0x0400);
+        assert_eq!(regs.sp, 0xfc);
+        assert!(regs.flags.contains(CpuFlags::INT_DISABLE));
+        assert_eq!(emulator.get_cycles(), cycles + 7);
+    }
+
+    #[test]
+    fn brk_pushes_the_address_after_its_padding_byte_and_rti_returns_there() {
+        let mut emulator = load_program("sec\nbrk\nnop");
+        set_vector(&mut emulator, IRQ_VEC_LOW_ADDR, 0x0300);
+        // The handler clears the carry, which rti restores
+        emulator.get_bus_mut().write(0x0300, 0x18);
+        emulator.get_bus_mut().write(0x0301, 0x40);
+        emulator.step();
+        let result = emulator.step();
+        assert!(result.halted);
+        assert_eq!(result.interrupt, Some(InterruptKind::Brk));
+        assert_eq!(emulator.get_regs().pc, 0x0300);
+        let bus = emulator.get_bus();
+        assert_eq!((bus.read(0x01ff), bus.read(0x01fe)), (0x02, 0x03));
+        let pushed = CpuFlags::from_bits_retain(bus.read(0x01fd));
+        assert_eq!(pushed, CpuFlags::CARRY | CpuFlags::BREAK | CpuFlags::UNUSED);
+
+        emulator.step();
+        emulator.step();
+        let regs = emulator.get_regs();
+        assert_eq!(regs.pc, 0x0203);
+        assert_eq!(regs.sp, 0xff);
+        assert_eq!(regs.flags, CpuFlags::CARRY);
+    }
+
+    #[test]
+    fn zero_page_x_wraps_within_the_zero_page() {
+        let mut emulator = load_program("ldx #$01\nlda $ff,x");
+        emulator.get_bus_mut().write(0x0000, 0x11);
+        emulator.get_bus_mut().write(0x0100, 0x22);
+        emulator.step();
+        emulator.step();
+        assert_eq!(emulator.get_regs().a, 0x11);
+    }
+
+    #[test]
+    fn indirect_y_pointer_wraps_within_the_zero_page() {
+        let mut emulator = load_program("ldy #$01\nlda ($ff),y");
+        let bus = emulator.get_bus_mut();
+        bus.write(0x00ff, 0x00);
+        bus.write(0x0000, 0x30);
+        bus.write(0x0100, 0x40);
+        bus.write(0x3001, 0x55);
+        emulator.step();
+        emulator.step();
+        assert_eq!(emulator.get_regs().a, 0x55);
+    }
+
+    #[test]
+    fn indirect_jmp_wraps_within_the_page_except_on_the_65c02() {
+        for (variant, target) in [
+            (CpuVariant::Nmos6502, 0x4000),
+            (CpuVariant::Ricoh2A03, 0x4000),
+            (CpuVariant::Cmos65C02, 0x5000),
+        ] {
+            let mut emulator = load_program("jmp ($10ff)");
+            emulator.set_variant(variant);
+            let bus = emulator.get_bus_mut();
+            bus.write(0x10ff, 0x00);
+            bus.write(0x1000, 0x40);
+            bus.write(0x1100, 0x50);
+            emulator.step();
+            assert_eq!(emulator.get_regs().pc, target, "{variant:?}");
+        }
+    }
+
+    #[test]
+    fn stack_overflows_only_when_the_stack_pointer_wraps() {
+        let mut emulator = load_program("ldx #$02\ntxs\nlda #$aa\npha\npha\npha");
+        for _ in 0..5 {
+            emulator.try_step().unwrap();
+        }
+        assert_eq!(emulator.get_regs().sp, 0x00);
+        assert_eq!(emulator.get_bus().read(0x0101), 0xaa);
+
+        // The last push still writes to $0100 before the stack pointer wraps
+        let error = emulator.try_step().unwrap_err();
+        assert_eq!(error.kind, EmulatorErrorKind::StackOverflow);
+        assert_eq!(emulator.get_bus().read(0x0100), 0xaa);
+        assert_eq!(emulator.get_regs().sp, 0xff);
+    }
+
+    #[test]
+    fn runs_stop_when_the_budget_is_exhausted() {
+        let mut emulator = load_program("loop:\ninx\njmp loop");
+        let reason = emulator.run_for_instructions(5).unwrap();
+        assert_eq!(reason, StopReason::BudgetExhausted);
+        assert_eq!(emulator.get_regs().x, 3);
+
+        // inx and jmp take 2 and 3 cycles, so the last instruction overshoots by one
+        let reason = emulator.run_for_cycles(9).unwrap();
+        assert_eq!(reason, StopReason::BudgetExhausted);
+        assert_eq!(emulator.get_cycles(), 12 + 10);
+
+        let reason = emulator.run_until(|emulator| emulator.get_regs().x == 10);
+        assert_eq!(reason.unwrap(), StopReason::Condition);
+    }
+
+    #[test]
+    fn runs_stop_at_jumps_and_branches_to_themselves() {
+        let mut emulator = load_program("nop\ntrap:\njmp trap");
+        let reason = emulator.run_for_instructions(100).unwrap();
+        assert_eq!(reason, StopReason::TrapDetected(0x0201));
+
+        let mut emulator = load_program("lda #$00\ntrap:\nbeq trap");
+        let reason = emulator.run_for_instructions(100).unwrap();
+        assert_eq!(reason, StopReason::TrapDetected(0x0202));
+    }
+
+    #[test]
+    fn a_jump_to_itself_is_not_a_trap_while_an_irq_can_be_taken() {
+        let mut emulator = load_program("cli\ntrap:\njmp trap");
+        set_vector(&mut emulator, IRQ_VEC_LOW_ADDR, 0x0300);
+        emulator.get_bus_mut().write(0x0300, 0x40);
+        emulator.get_regs_mut().flags.insert(CpuFlags::INT_DISABLE);
+        emulator.assert_irq();
+        let reason = emulator.run_for_instructions(4).unwrap();
+        assert_eq!(reason, StopReason::BudgetExhausted);
+    }
+
+    #[test]
+    fn run_stops_at_address_and_op_code_breakpoints() {
+        let source = "
+            .org $0200
+            start: ldx #$02
+            loop: dex
+                bne loop
+                brk
+            .org $fffc
+            .word start";
+        let mut emulator = Emulator::new(Memory::new_from_bytes(assemble(source).unwrap()));
+        let breakpoints = emulator.get_breakpoints_mut();
+        let at_loop = breakpoints.add(BreakpointKind::Address(0x0202));
+        let at_bne = breakpoints.add(BreakpointKind::OpCode(0xd0));
+
+        assert_eq!(emulator.run_until_break(), Some(at_loop));
+        assert_eq!(emulator.get_regs().pc, 0x0202);
+        // Resuming steps over the breakpoint execution stopped at
+        let stop = emulator.run_until(|_| false).unwrap();
+        assert_eq!(stop, StopReason::Breakpoint(at_bne));
+        let stop = emulator.run_until(|_| false).unwrap();
+        assert_eq!(stop, StopReason::Breakpoint(at_loop));
+        let stop = emulator.run_until(|_| false).unwrap();
+        assert_eq!(stop, StopReason::Breakpoint(at_bne));
+        let stop = emulator.run_until(|_| false).unwrap();
+        assert_eq!(stop, StopReason::Break);
+
+        let breakpoints = emulator.get_breakpoints();
+        assert_eq!(breakpoints.get(at_loop).unwrap().hits, 2);
+        assert_eq!(breakpoints.get(at_bne).unwrap().hits, 2);
+    }
+
+    // Logs the address of every read the program makes. Peeks are not logged, like a device
+    // whose registers only see real reads.
+    struct LoggingBus {
+        memory: Memory,
+        reads: Rc<RefCell<Vec<u16>>>,
+    }
+
+    impl ReadWritable for LoggingBus {
+        fn read(&self, address: u16) -> u8 {
+            self.reads.borrow_mut().push(address);
+            self.memory.read(address)
+        }
+
+        fn write(&mut self, address: u16, byte: u8) {
+            self.memory.write(address, byte)
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.memory.read(address)
+        }
+    }
+
+    fn load_logged_program(source: &str) -> (Emulator<LoggingBus>, Rc<RefCell<Vec<u16>>>) {
+        let image = assemble(&format!(".org $0200\n{source}\nbrk\n")).unwrap();
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let mut emulator = Emulator::new(LoggingBus {
+            memory: Memory::new_from_bytes(image),
+            reads: reads.clone(),
+        });
+        emulator.get_regs_mut().pc = 0x0200;
+        (emulator, reads)
+    }
+
+    #[test]
+    fn instruction_bytes_are_read_once() {
+        let (mut emulator, reads) = load_logged_program("lda $10");
+        emulator
+            .get_breakpoints_mut()
+            .add(BreakpointKind::OpCode(0xea));
+        emulator.run_for_instructions(1).unwrap();
+        assert_eq!(*reads.borrow(), [0x0200, 0x0201, 0x0010]);
+    }
+
+    #[test]
+    fn recording_writes_does_not_read_the_bus() {
+        let (mut emulator, reads) = load_logged_program("lda #$aa\nsta $10");
+        emulator.get_bus_mut().write(0x10, 0x55);
+        emulator.enable_history(4);
+        emulator.step();
+        emulator.step();
+        assert_eq!(emulator.get_bus().peek(0x10), 0xaa);
+        assert!(!reads.borrow().contains(&0x10));
+
+        assert!(emulator.step_back());
+        assert_eq!(emulator.get_bus().peek(0x10), 0x55);
+    }
+
+    #[test]
+    fn tracing_does_not_read_the_bus() {
+        let (mut emulator, reads) = load_logged_program("lda $10");
+        emulator.enable_tracing(Box::new(std::io::sink()), TraceFormat::Nestest);
+        emulator.step();
+        assert_eq!(*reads.borrow(), [0x0200, 0x0201, 0x0010]);
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read(u16, u8),
+        Write(u16, u8, u8),
+        Push(u16, u8),
+    }
+
+    impl EmulatorObserver for Vec<Event> {
+        fn on_read(&mut self, address: u16, value: u8) {
+            self.push(Event::Read(address, value));
+        }
+
+        fn on_write(&mut self, address: u16, old: u8, new: u8) {
+            self.push(Event::Write(address, old, new));
+        }
+
+        fn on_stack_push(&mut self, address: u16, value: u8) {
+            self.push(Event::Push(address, value));
+        }
+    }
+
+    #[test]
+    fn observers_see_data_accesses_without_extra_bus_reads() {
+        let (mut emulator, reads) = load_logged_program("lda $10\nsta $11\npha");
+        emulator.get_bus_mut().write(0x10, 0x42);
+        emulator.get_bus_mut().write(0x11, 0x07);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        emulator.add_observer(Box::new(events.clone()));
+        for _ in 0..3 {
+            emulator.step();
+        }
+        assert_eq!(
+            *events.borrow(),
+            [
+                Event::Read(0x10, 0x42),
+                Event::Write(0x11, 0x07, 0x42),
+                Event::Write(0x01ff, 0x00, 0x42),
+                Event::Push(0x01ff, 0x42),
+            ]
+        );
+        assert_eq!(
+            *reads.borrow(),
+            [0x0200, 0x0201, 0x0010, 0x0202, 0x0203, 0x0204]
+        );
+    }
+
+    #[test]
+    fn step_result_records_whether_branches_are_taken() {
+        let mut emulator = load_program("lda #$00\nbeq skip\nnop\nskip: bne skip");
+        assert_eq!(emulator.step().branch_taken, None);
+        let taken = emulator.step();
+        assert_eq!(taken.branch_taken, Some(true));
+        assert_eq!(taken.cycles, 3);
+        let not_taken = emulator.step();
+        assert_eq!(not_taken.branch_taken, Some(false));
+        assert_eq!(not_taken.cycles, 2);
+    }
+
+    #[test]
+    fn step_back_reverts_the_call_stack() {
+        let mut emulator =
+            load_program("jsr outer\nbrk\nouter: jsr inner\nrts\ninner: ldx #$ff\ntxs");
+        emulator.enable_history(16);
+        let mut frames = vec![emulator.get_call_stack().get_frames().to_vec()];
+        while !emulator.step().halted {
+            frames.push(emulator.get_call_stack().get_frames().to_vec());
+        }
+        while let Some(expected) = frames.pop() {
+            assert!(emulator.step_back());
+            assert_eq!(emulator.get_call_stack().get_frames(), expected);
+        }
+    }
+
+    #[test]
+    fn call_stack_depth_is_capped() {
+        let mut emulator = load_program("recurse: jsr recurse");
+        emulator.set_stack_policy(FaultPolicy::Continue);
+        emulator.enable_history(300);
+        for _ in 0..200 {
+            emulator.step();
+        }
+        let frames = emulator.get_call_stack().get_frames().to_vec();
+        for _ in 0..100 {
+            emulator.step();
+        }
+        assert_eq!(
+            emulator.get_call_stack().get_frames().len(),
+            MAX_CALL_STACK_DEPTH
+        );
+
+        // Stepping back restores the outermost frames that were dropped
+        for _ in 0..100 {
+            emulator.step_back();
+        }
+        assert_eq!(emulator.get_call_stack().get_frames(), frames);
+        while emulator.step_back() {}
+        assert!(emulator.get_call_stack().get_frames().is_empty());
     }
 }
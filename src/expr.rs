@@ -0,0 +1,494 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use crate::assembler::parse_number;
+use crate::readwritable::ReadWritable;
+use crate::regs::{CpuFlags, Regs};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExprErrorKind {
+    UnexpectedChar(char),
+    UnexpectedEnd,
+    UnexpectedToken(String),
+    InvalidNumber(String),
+    UnknownIdentifier(String),
+}
+
+impl Display for ExprErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExprErrorKind::UnexpectedChar(c) => write!(f, "unexpected character '{c}'"),
+            ExprErrorKind::UnexpectedEnd => write!(f, "unexpected end of expression"),
+            ExprErrorKind::UnexpectedToken(token) => write!(f, "unexpected '{token}'"),
+            ExprErrorKind::InvalidNumber(number) => write!(f, "invalid number '{number}'"),
+            ExprErrorKind::UnknownIdentifier(name) => {
+                write!(f, "'{name}' is neither a register nor a flag")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    /// The byte offset into the source where the error was found
+    pub position: usize,
+    pub kind: ExprErrorKind,
+}
+
+impl Display for ExprError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "column {}: {}", self.position + 1, self.kind)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    Sp,
+    Pc,
+    /// The status register as a byte
+    P,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Complement,
+    Negate,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+impl BinaryOp {
+    // Same precedence as in Rust, so `p & 1 == 1` is `(p & 1) == 1`
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Eq
+            | BinaryOp::Ne
+            | BinaryOp::Lt
+            | BinaryOp::Le
+            | BinaryOp::Gt
+            | BinaryOp::Ge => 3,
+            BinaryOp::BitOr => 4,
+            BinaryOp::BitXor => 5,
+            BinaryOp::BitAnd => 6,
+            BinaryOp::Add | BinaryOp::Sub => 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Number(i64),
+    Register(Register),
+    Flag(CpuFlags),
+    /// The byte at an address, written `[address]`
+    Memory(Box<Node>),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    pub fn evaluate(&self, regs: &Regs, bus: &dyn ReadWritable) -> i64 {
+        match self {
+            Node::Number(value) => *value,
+            Node::Register(register) => match register {
+                Register::A => regs.a as i64,
+                Register::X => regs.x as i64,
+                Register::Y => regs.y as i64,
+                Register::Sp => regs.sp as i64,
+                Register::Pc => regs.pc as i64,
+                Register::P => regs.flags.bits() as i64,
+            },
+            Node::Flag(flag) => regs.flags.contains(*flag) as i64,
+            Node::Memory(address) => bus.peek(address.evaluate(regs, bus) as u16) as i64,
+            Node::Unary(op, node) => {
+                let value = node.evaluate(regs, bus);
+                match op {
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Complement => !value,
+                    UnaryOp::Negate => value.wrapping_neg(),
+                }
+            }
+            Node::Binary(BinaryOp::Or, lhs, rhs) => {
+                (lhs.evaluate(regs, bus) != 0 || rhs.evaluate(regs, bus) != 0) as i64
+            }
+            Node::Binary(BinaryOp::And, lhs, rhs) => {
+                (lhs.evaluate(regs, bus) != 0 && rhs.evaluate(regs, bus) != 0) as i64
+            }
+            Node::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(regs, bus);
+                let rhs = rhs.evaluate(regs, bus);
+                match op {
+                    BinaryOp::Eq => (lhs == rhs) as i64,
+                    BinaryOp::Ne => (lhs != rhs) as i64,
+                    BinaryOp::Lt => (lhs < rhs) as i64,
+                    BinaryOp::Le => (lhs <= rhs) as i64,
+                    BinaryOp::Gt => (lhs > rhs) as i64,
+                    BinaryOp::Ge => (lhs >= rhs) as i64,
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Or | BinaryOp::And => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+/// A condition over the registers, flags and memory, e.g. `a == $10 && [$00f7] > 3 && !C`.
+///
+/// Registers are `a`, `x`, `y`, `sp`, `pc` and `p`, flags are either their `CpuFlags` name or
+/// one of `N V B D I Z C`, and `[address]` reads a byte. Numbers are written like in the
+/// assembler. Any non zero value is true.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self, ExprError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            end: source.len(),
+        };
+        let root = parser.parse_binary(0)?;
+        if let Some((position, token)) = parser.tokens.get(parser.position) {
+            return Err(ExprError {
+                position: *position,
+                kind: ExprErrorKind::UnexpectedToken(token.to_string()),
+            });
+        }
+        Ok(Self {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn get_root(&self) -> &Node {
+        &self.root
+    }
+
+    pub fn evaluate(&self, regs: &Regs, bus: &dyn ReadWritable) -> i64 {
+        self.root.evaluate(regs, bus)
+    }
+
+    pub fn is_true(&self, regs: &Regs, bus: &dyn ReadWritable) -> bool {
+        self.evaluate(regs, bus) != 0
+    }
+}
+
+impl FromStr for Expression {
+    type Err = ExprError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Identifier(String),
+    Binary(BinaryOp),
+    Not,
+    Complement,
+    Minus,
+    OpenParen,
+    CloseParen,
+    OpenBracket,
+    CloseBracket,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Token::Number(value) => return write!(f, "{value}"),
+            Token::Identifier(name) => name.as_str(),
+            Token::Binary(op) => match op {
+                BinaryOp::Or => "||",
+                BinaryOp::And => "&&",
+                BinaryOp::Eq => "==",
+                BinaryOp::Ne => "!=",
+                BinaryOp::Lt => "<",
+                BinaryOp::Le => "<=",
+                BinaryOp::Gt => ">",
+                BinaryOp::Ge => ">=",
+                BinaryOp::BitOr => "|",
+                BinaryOp::BitXor => "^",
+                BinaryOp::BitAnd => "&",
+                BinaryOp::Add => "+",
+                BinaryOp::Sub => "-",
+            },
+            Token::Not => "!",
+            Token::Complement => "~",
+            Token::Minus => "-",
+            Token::OpenParen => "(",
+            Token::CloseParen => ")",
+            Token::OpenBracket => "[",
+            Token::CloseBracket => "]",
+        };
+        write!(f, "{text}")
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExprError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        let next = bytes.get(i + 1).map(|&b| b as char);
+        let start = i;
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('|', Some('|')) => (Token::Binary(BinaryOp::Or), 2),
+            ('&', Some('&')) => (Token::Binary(BinaryOp::And), 2),
+            ('=', Some('=')) => (Token::Binary(BinaryOp::Eq), 2),
+            ('!', Some('=')) => (Token::Binary(BinaryOp::Ne), 2),
+            ('<', Some('=')) => (Token::Binary(BinaryOp::Le), 2),
+            ('>', Some('=')) => (Token::Binary(BinaryOp::Ge), 2),
+            ('<', _) => (Token::Binary(BinaryOp::Lt), 1),
+            ('>', _) => (Token::Binary(BinaryOp::Gt), 1),
+            ('|', _) => (Token::Binary(BinaryOp::BitOr), 1),
+            ('^', _) => (Token::Binary(BinaryOp::BitXor), 1),
+            ('&', _) => (Token::Binary(BinaryOp::BitAnd), 1),
+            ('+', _) => (Token::Binary(BinaryOp::Add), 1),
+            ('-', _) => (Token::Minus, 1),
+            ('!', _) => (Token::Not, 1),
+            ('~', _) => (Token::Complement, 1),
+            ('(', _) => (Token::OpenParen, 1),
+            (')', _) => (Token::CloseParen, 1),
+            ('[', _) => (Token::OpenBracket, 1),
+            (']', _) => (Token::CloseBracket, 1),
+            ('$', _) | ('%', _) | ('0'..='9', _) => {
+                let len = 1 + word_len(&source[i + 1..]);
+                let text = &source[i..i + len];
+                let value = parse_number(text).ok_or(ExprError {
+                    position: i,
+                    kind: ExprErrorKind::InvalidNumber(text.to_string()),
+                })?;
+                (Token::Number(value), len)
+            }
+            (c, _) if c.is_ascii_alphabetic() || c == '_' => {
+                let len = word_len(&source[i..]);
+                (Token::Identifier(source[i..i + len].to_string()), len)
+            }
+            (c, _) => {
+                return Err(ExprError {
+                    position: i,
+                    kind: ExprErrorKind::UnexpectedChar(c),
+                })
+            }
+        };
+        tokens.push((start, token));
+        i += len;
+    }
+    Ok(tokens)
+}
+
+fn word_len(text: &str) -> usize {
+    text.bytes()
+        .take_while(|b| b.is_ascii_alphanumeric() || *b == b'_')
+        .count()
+}
+
+fn parse_identifier(name: &str) -> Option<Node> {
+    let register = match name.to_ascii_lowercase().as_str() {
+        "a" => Some(Register::A),
+        "x" => Some(Register::X),
+        "y" => Some(Register::Y),
+        "sp" => Some(Register::Sp),
+        "pc" => Some(Register::Pc),
+        "p" => Some(Register::P),
+        _ => None,
+    };
+    if let Some(register) = register {
+        return Some(Node::Register(register));
+    }
+    let upper = name.to_ascii_uppercase();
+    let flag = match upper.as_str() {
+        "N" => CpuFlags::NEG,
+        "V" => CpuFlags::OVERFLOW,
+        "B" => CpuFlags::BREAK,
+        "D" => CpuFlags::DEC_MODE,
+        "I" => CpuFlags::INT_DISABLE,
+        "Z" => CpuFlags::ZERO,
+        "C" => CpuFlags::CARRY,
+        _ => CpuFlags::from_name(&upper)?,
+    };
+    Some(Node::Flag(flag))
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn next(&mut self) -> Result<(usize, Token), ExprError> {
+        let token = self.tokens.get(self.position).cloned().ok_or(ExprError {
+            position: self.end,
+            kind: ExprErrorKind::UnexpectedEnd,
+        })?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ExprError> {
+        let (position, token) = self.next()?;
+        if token != expected {
+            return Err(ExprError {
+                position,
+                kind: ExprErrorKind::UnexpectedToken(token.to_string()),
+            });
+        }
+        Ok(())
+    }
+
+    fn peek_binary(&self) -> Option<BinaryOp> {
+        match self.peek()? {
+            Token::Binary(op) => Some(*op),
+            Token::Minus => Some(BinaryOp::Sub),
+            _ => None,
+        }
+    }
+
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Node, ExprError> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(op) = self.peek_binary() {
+            if op.precedence() <= min_precedence {
+                break;
+            }
+            self.position += 1;
+            let rhs = self.parse_binary(op.precedence())?;
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Node, ExprError> {
+        let (position, token) = self.next()?;
+        Ok(match token {
+            Token::Not => Node::Unary(UnaryOp::Not, Box::new(self.parse_unary()?)),
+            Token::Complement => Node::Unary(UnaryOp::Complement, Box::new(self.parse_unary()?)),
+            Token::Minus => Node::Unary(UnaryOp::Negate, Box::new(self.parse_unary()?)),
+            Token::Number(value) => Node::Number(value),
+            Token::Identifier(name) => parse_identifier(&name).ok_or(ExprError {
+                position,
+                kind: ExprErrorKind::UnknownIdentifier(name),
+            })?,
+            Token::OpenParen => {
+                let node = self.parse_binary(0)?;
+                self.expect(Token::CloseParen)?;
+                node
+            }
+            Token::OpenBracket => {
+                let node = self.parse_binary(0)?;
+                self.expect(Token::CloseBracket)?;
+                Node::Memory(Box::new(node))
+            }
+            token => {
+                return Err(ExprError {
+                    position,
+                    kind: ExprErrorKind::UnexpectedToken(token.to_string()),
+                })
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::Memory;
+
+    fn parse(source: &str) -> Node {
+        Expression::parse(source).unwrap().get_root().clone()
+    }
+
+    fn binary(op: BinaryOp, lhs: Node, rhs: Node) -> Node {
+        Node::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    #[test]
+    fn logical_operators_bind_looser_than_comparisons() {
+        let expected = binary(
+            BinaryOp::And,
+            binary(
+                BinaryOp::Eq,
+                Node::Register(Register::A),
+                Node::Number(0x10),
+            ),
+            Node::Unary(UnaryOp::Not, Box::new(Node::Flag(CpuFlags::CARRY))),
+        );
+        assert_eq!(parse("a == $10 && !C"), expected);
+    }
+
+    #[test]
+    fn brackets_read_memory() {
+        let expected = binary(
+            BinaryOp::Gt,
+            Node::Memory(Box::new(Node::Number(0xf7))),
+            Node::Number(3),
+        );
+        assert_eq!(parse("[$00f7] > 3"), expected);
+
+        let mut memory = Memory::new();
+        memory.write(0x00f7, 4);
+        let expression = Expression::parse("[$00f7] > 3").unwrap();
+        assert!(expression.is_true(&Regs::new(), &memory));
+    }
+
+    #[test]
+    fn trailing_operators_are_errors() {
+        for source in ["a ==", "a == 1 &&", "[$00f7] > 3 +"] {
+            let error = Expression::parse(source).unwrap_err();
+            assert_eq!(error.kind, ExprErrorKind::UnexpectedEnd, "{source}");
+            assert_eq!(
+                error.to_string(),
+                format!("column {}: unexpected end of expression", source.len() + 1)
+            );
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+
+use crate::callstack::CallStackChange;
+use crate::regs::Regs;
+
+/// What an instruction changed, enough to undo it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// The registers before the instruction
+    pub regs: Regs,
+    /// The cycle counter before the instruction
+    pub cycles: u64,
+    pub nmi_pending: bool,
+    pub stop_signalled: bool,
+    /// How the instruction changed the shadow call stack, in order
+    pub call_stack_changes: Vec<CallStackChange>,
+    /// The address and previous value of every byte the instruction wrote, in order
+    pub writes: Vec<(u16, u8)>,
+}
+
+/// A ring buffer of the last `capacity` instructions, the oldest are dropped first
+#[derive(Debug, Clone)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn get_capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The cycle counter before the oldest instruction that can be undone
+    pub fn get_oldest_cycle(&self) -> Option<u64> {
+        self.entries.front().map(|entry| entry.cycles)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Records a write on the most recent instruction
+    pub fn record_write(&mut self, address: u16, old: u8) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.writes.push((address, old));
+        }
+    }
+
+    /// Records a call stack change on the most recent instruction
+    pub fn record_call_stack_change(&mut self, change: CallStackChange) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.call_stack_changes.push(change);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<HistoryEntry> {
+        self.entries.pop_back()
+    }
+}
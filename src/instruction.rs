@@ -46,21 +46,66 @@ pub enum AddressingMode {
     IndirectY,
 }
 
-#[derive(Debug, Copy, Clone)]
+impl AddressingMode {
+    pub const fn operand_size(&self) -> u16 {
+        match self {
+            AddressingMode::Implicit | AddressingMode::Accumulator => 0,
+
+            AddressingMode::Immediate
+            | AddressingMode::ZeroPage
+            | AddressingMode::ZeroPageX
+            | AddressingMode::ZeroPageY
+            | AddressingMode::Relative
+            | AddressingMode::IndirectX
+            | AddressingMode::IndirectY => 1,
+
+            AddressingMode::Absolute
+            | AddressingMode::AbsoluteX
+            | AddressingMode::AbsoluteY
+            | AddressingMode::Indirect => 2,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Instruction {
     pub name: InstructionName,
     pub addressing_mode: AddressingMode,
     pub op_code: u8,
     pub operand: u16,
+    pub cycles: u8,
 }
 
 impl Instruction {
-    fn new(name: InstructionName, addressing_mode: AddressingMode, op_code: u8, operand: u16) -> Self {
+    fn new(name: InstructionName, addressing_mode: AddressingMode, op_code: u8, operand: u16, cycles: u8) -> Self {
         Self {
             name,
             addressing_mode,
             op_code,
-            operand
+            operand,
+            cycles,
+        }
+    }
+
+    pub const fn size(&self) -> u16 {
+        1 + self.addressing_mode.operand_size()
+    }
+
+    /// Where a branch at `address` jumps to when taken, or `None` for other instructions
+    pub fn get_branch_target(&self, address: u16) -> Option<u16> {
+        if self.addressing_mode != AddressingMode::Relative {
+            return None;
+        }
+        let next = address.wrapping_add(self.size());
+        Some(next.wrapping_add(self.operand as u8 as i8 as u16))
+    }
+
+    /// Like `Display`, but branches show the address they jump to when the instruction is at
+    /// `address`
+    pub fn disassemble(&self, address: u16) -> String {
+        match self.get_branch_target(address) {
+            Some(target) => format!("{} ${target:04x}", self.name),
+            None => self.to_string(),
         }
     }
 }
@@ -110,7 +155,8 @@ impl Display for Instruction {
 #[derive(Debug)]
 pub struct InstructionBuilder {
     pub name: InstructionName,
-    addressing_modes: HashMap<AddressingMode, u8>
+    addressing_modes: HashMap<AddressingMode, u8>,
+    cycles: HashMap<AddressingMode, u8>,
 }
 
 impl InstructionBuilder {
@@ -118,6 +164,7 @@ impl InstructionBuilder {
         Self {
             name,
             addressing_modes: HashMap::new(),
+            cycles: HashMap::new(),
         }
     }
     
@@ -125,43 +172,92 @@ impl InstructionBuilder {
         &self.addressing_modes
     }
 
-    fn add_mode(mut self, addressing_mode: AddressingMode, op_code: u8) -> Self {
+    pub fn get_cycles(&self, addressing_mode: AddressingMode) -> Option<u8> {
+        self.cycles.get(&addressing_mode).copied()
+    }
+
+    fn add_mode(mut self, addressing_mode: AddressingMode, op_code: u8, cycles: u8) -> Self {
         _ = self.addressing_modes.insert(addressing_mode, op_code);
+        _ = self.cycles.insert(addressing_mode, cycles);
         self
     }
 
     pub fn build(&self, addressing_mode: AddressingMode, operand: u16) -> Option<Instruction> {
         let op_code = self.addressing_modes.get(&addressing_mode)?.clone();
-        Some(Instruction::new(self.name, addressing_mode, op_code, operand))
+        let cycles = self.get_cycles(addressing_mode)?;
+        Some(Instruction::new(self.name, addressing_mode, op_code, operand, cycles))
     }
 
-    fn imp(self, op_code: u8) -> Self { self.add_mode(AddressingMode::Implicit, op_code) }
-    fn acc(self, op_code: u8) -> Self { self.add_mode(AddressingMode::Accumulator, op_code) }
-    fn imm(self, op_code: u8) -> Self { self.add_mode(AddressingMode::Immediate, op_code) }
-    fn zp(self, op_code: u8) -> Self { self.add_mode(AddressingMode::ZeroPage, op_code) }
-    fn zpx(self, op_code: u8) -> Self { self.add_mode(AddressingMode::ZeroPageX, op_code) }
-    fn zpy(self, op_code: u8) -> Self { self.add_mode(AddressingMode::ZeroPageY, op_code) }
-    fn rel(self, op_code: u8) -> Self { self.add_mode(AddressingMode::Relative, op_code) }
-    fn abs(self, op_code: u8) -> Self { self.add_mode(AddressingMode::Absolute, op_code) }
-    fn absx(self, op_code: u8) -> Self { self.add_mode(AddressingMode::AbsoluteX, op_code) }
-    fn absy(self, op_code: u8) -> Self { self.add_mode(AddressingMode::AbsoluteY, op_code) }
-    fn ind(self, op_code: u8) -> Self { self.add_mode(AddressingMode::Indirect, op_code) }
-    fn indx(self, op_code: u8) -> Self { self.add_mode(AddressingMode::IndirectX, op_code) }
-    fn indy(self, op_code: u8) -> Self { self.add_mode(AddressingMode::IndirectY, op_code) }
+    fn imp(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::Implicit, op_code, cycles) }
+    fn acc(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::Accumulator, op_code, cycles) }
+    fn imm(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::Immediate, op_code, cycles) }
+    fn zp(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::ZeroPage, op_code, cycles) }
+    fn zpx(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::ZeroPageX, op_code, cycles) }
+    fn zpy(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::ZeroPageY, op_code, cycles) }
+    fn rel(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::Relative, op_code, cycles) }
+    fn abs(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::Absolute, op_code, cycles) }
+    fn absx(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::AbsoluteX, op_code, cycles) }
+    fn absy(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::AbsoluteY, op_code, cycles) }
+    fn ind(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::Indirect, op_code, cycles) }
+    fn indx(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::IndirectX, op_code, cycles) }
+    fn indy(self, op_code: u8, cycles: u8) -> Self { self.add_mode(AddressingMode::IndirectY, op_code, cycles) }
+}
+
+/// What an op code decodes to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OpcodeInfo {
+    pub name: InstructionName,
+    pub addressing_mode: AddressingMode,
+    /// The size of the instruction in bytes, including the op code
+    pub length: u16,
+    /// The base cycles, before page crossing and branch penalties
+    pub cycles: u8,
 }
 
 const NUM_INSTRUCTIONS: usize = 56;
+const NUM_OP_CODES: usize = 256;
 pub struct InstructionRegistry {
     pub all_instructions: [InstructionBuilder; NUM_INSTRUCTIONS],
+    op_codes: [Option<OpcodeInfo>; NUM_OP_CODES],
 }
 
 impl InstructionRegistry {
     pub fn new() -> Self {
+        let all_instructions = Self::get_all_instructions();
+        let op_codes = Self::get_op_codes(&all_instructions);
         Self {
-            all_instructions: Self::get_all_instructions(),
+            all_instructions,
+            op_codes,
         }
     }
 
+    /// Panics if two addressing modes claim the same op code
+    fn get_op_codes(builders: &[InstructionBuilder]) -> [Option<OpcodeInfo>; NUM_OP_CODES] {
+        let mut op_codes: [Option<OpcodeInfo>; NUM_OP_CODES] = [None; NUM_OP_CODES];
+        for builder in builders {
+            for (&addressing_mode, &op_code) in &builder.addressing_modes {
+                let info = OpcodeInfo {
+                    name: builder.name,
+                    addressing_mode,
+                    length: 1 + addressing_mode.operand_size(),
+                    cycles: builder.cycles[&addressing_mode],
+                };
+                if let Some(other) = op_codes[op_code as usize] {
+                    panic!(
+                        "Op code {op_code:#04x} is claimed by both {} {} and {} {}",
+                        other.name, other.addressing_mode, info.name, info.addressing_mode
+                    );
+                }
+                op_codes[op_code as usize] = Some(info);
+            }
+        }
+        op_codes
+    }
+
+    pub fn get_opcode_info(&self, op_code: u8) -> Option<&OpcodeInfo> {
+        self.op_codes[op_code as usize].as_ref()
+    }
+
     pub fn get_instruction_by_name(&self, name: InstructionName) -> &InstructionBuilder {
         if let Some(builder) = self.all_instructions.iter().find(|builder| {
             builder.name == name
@@ -172,256 +268,276 @@ impl InstructionRegistry {
         }
     }
 
+    #[inline]
     pub fn get_instruction_by_op_code(&self, op_code: u8, operand: u16) -> Option<Instruction> {
-        let mut name: Option<InstructionName> = None;
-        let mut addr_mode: Option<AddressingMode> = None;
-
-        for ins in &self.all_instructions {
-            for (mode, mode_op_code) in &ins.addressing_modes {
-                if *mode_op_code == op_code {
-                    name = Some(ins.name);
-                    addr_mode = Some(*mode);
-                }
-            }
-        }
-
-        if let Some(name) = name && let Some(addr_mode) = addr_mode {
-            Some(Instruction::new(name, addr_mode, op_code, operand))
-        } else {
-            None
-        }
+        let info = self.op_codes[op_code as usize]?;
+        Some(Instruction::new(info.name, info.addressing_mode, op_code, operand, info.cycles))
     }
     
     fn get_all_instructions() -> [InstructionBuilder; NUM_INSTRUCTIONS] {
         [
             // Load/store operations
             InstructionBuilder::new(InstructionName::lda)
-                .imm(0xa9)
-                .zp(0xa5)
-                .zpx(0xb5)
-                .abs(0xad)
-                .absx(0xbd)
-                .absy(0xb9)
-                .indx(0xa1)
-                .indy(0xb1),
+                .imm(0xa9, 2)
+                .zp(0xa5, 3)
+                .zpx(0xb5, 4)
+                .abs(0xad, 4)
+                .absx(0xbd, 4)
+                .absy(0xb9, 4)
+                .indx(0xa1, 6)
+                .indy(0xb1, 5),
             InstructionBuilder::new(InstructionName::ldx)
-                .imm(0xa2)
-                .zp(0xa6)
-                .zpy(0xb6)
-                .abs(0xae)
-                .absy(0xbe),
+                .imm(0xa2, 2)
+                .zp(0xa6, 3)
+                .zpy(0xb6, 4)
+                .abs(0xae, 4)
+                .absy(0xbe, 4),
             InstructionBuilder::new(InstructionName::ldy)
-                .imm(0xa0)
-                .zp(0xa4)
-                .zpx(0xb4)
-                .abs(0xac)
-                .absx(0xbc),
+                .imm(0xa0, 2)
+                .zp(0xa4, 3)
+                .zpx(0xb4, 4)
+                .abs(0xac, 4)
+                .absx(0xbc, 4),
             InstructionBuilder::new(InstructionName::sta)
-                .zp(0x85)
-                .zpx(0x95)
-                .abs(0x8d)
-                .absx(0x9d)
-                .absy(0x99)
-                .indx(0x81)
-                .indy(0x91),
+                .zp(0x85, 3)
+                .zpx(0x95, 4)
+                .abs(0x8d, 4)
+                .absx(0x9d, 5)
+                .absy(0x99, 5)
+                .indx(0x81, 6)
+                .indy(0x91, 6),
             InstructionBuilder::new(InstructionName::stx)
-                .zp(0x86)
-                .zpy(0x96)
-                .abs(0x8e),
+                .zp(0x86, 3)
+                .zpy(0x96, 4)
+                .abs(0x8e, 4),
             InstructionBuilder::new(InstructionName::sty)
-                .zp(0x84)
-                .zpx(0x94)
-                .abs(0x8c),
+                .zp(0x84, 3)
+                .zpx(0x94, 4)
+                .abs(0x8c, 4),
 
             // Register transfers
             InstructionBuilder::new(InstructionName::tax)
-                .imp(0xaa),
+                .imp(0xaa, 2),
             InstructionBuilder::new(InstructionName::tay)
-                .imp(0xa8),
+                .imp(0xa8, 2),
             InstructionBuilder::new(InstructionName::txa)
-                .imp(0x8a),
+                .imp(0x8a, 2),
             InstructionBuilder::new(InstructionName::tya)
-                .imp(0x98),
+                .imp(0x98, 2),
 
             // Stack operations
             InstructionBuilder::new(InstructionName::tsx)
-                .imp(0xba),
+                .imp(0xba, 2),
             InstructionBuilder::new(InstructionName::txs)
-                .imp(0x9a),
+                .imp(0x9a, 2),
             InstructionBuilder::new(InstructionName::pha)
-                .imp(0x48),
+                .imp(0x48, 3),
             InstructionBuilder::new(InstructionName::php)
-                .imp(0x08),
+                .imp(0x08, 3),
             InstructionBuilder::new(InstructionName::pla)
-                .imp(0x68),
+                .imp(0x68, 4),
             InstructionBuilder::new(InstructionName::plp)
-                .imp(0x28),
+                .imp(0x28, 4),
 
             // Logical
             InstructionBuilder::new(InstructionName::and)
-                .imm(0x29)
-                .zp(0x25)
-                .zpx(0x35)
-                .abs(0x2d)
-                .absx(0x3d)
-                .absy(0x39)
-                .indx(0x21)
-                .indy(0x31),
+                .imm(0x29, 2)
+                .zp(0x25, 3)
+                .zpx(0x35, 4)
+                .abs(0x2d, 4)
+                .absx(0x3d, 4)
+                .absy(0x39, 4)
+                .indx(0x21, 6)
+                .indy(0x31, 5),
             InstructionBuilder::new(InstructionName::eor)
-                .imm(0x49)
-                .zp(0x45)
-                .zpx(0x55)
-                .abs(0x4d)
-                .absx(0x5d)
-                .absy(0x59)
-                .indx(0x41)
-                .indy(0x51),
+                .imm(0x49, 2)
+                .zp(0x45, 3)
+                .zpx(0x55, 4)
+                .abs(0x4d, 4)
+                .absx(0x5d, 4)
+                .absy(0x59, 4)
+                .indx(0x41, 6)
+                .indy(0x51, 5),
             InstructionBuilder::new(InstructionName::ora)
-                .imm(0x09)
-                .zp(0x05)
-                .zpx(0x15)
-                .abs(0x0d)
-                .absx(0x1d)
-                .absy(0x19)
-                .indx(0x01)
-                .indy(0x11),
+                .imm(0x09, 2)
+                .zp(0x05, 3)
+                .zpx(0x15, 4)
+                .abs(0x0d, 4)
+                .absx(0x1d, 4)
+                .absy(0x19, 4)
+                .indx(0x01, 6)
+                .indy(0x11, 5),
             InstructionBuilder::new(InstructionName::bit)
-                .zp(0x24)
-                .abs(0x2c),
+                .zp(0x24, 3)
+                .abs(0x2c, 4),
 
             // Arithmetic
             InstructionBuilder::new(InstructionName::adc)
-                .imm(0x69)
-                .zp(0x65)
-                .zpx(0x75)
-                .abs(0x6d)
-                .absx(0x7d)
-                .absy(0x79)
-                .indx(0x61)
-                .indy(0x71),
+                .imm(0x69, 2)
+                .zp(0x65, 3)
+                .zpx(0x75, 4)
+                .abs(0x6d, 4)
+                .absx(0x7d, 4)
+                .absy(0x79, 4)
+                .indx(0x61, 6)
+                .indy(0x71, 5),
             InstructionBuilder::new(InstructionName::sbc)
-                .imm(0xe9)
-                .zp(0xe5)
-                .zpx(0xf5)
-                .abs(0xed)
-                .absx(0xfd)
-                .absy(0xf9)
-                .indx(0xe1)
-                .indy(0xf1),
+                .imm(0xe9, 2)
+                .zp(0xe5, 3)
+                .zpx(0xf5, 4)
+                .abs(0xed, 4)
+                .absx(0xfd, 4)
+                .absy(0xf9, 4)
+                .indx(0xe1, 6)
+                .indy(0xf1, 5),
             InstructionBuilder::new(InstructionName::cmp)
-                .imm(0xc9)
-                .zp(0xc5)
-                .zpx(0xd5)
-                .abs(0xcd)
-                .absx(0xdd)
-                .absy(0xd9)
-                .indx(0xc1)
-                .indy(0xd1),
+                .imm(0xc9, 2)
+                .zp(0xc5, 3)
+                .zpx(0xd5, 4)
+                .abs(0xcd, 4)
+                .absx(0xdd, 4)
+                .absy(0xd9, 4)
+                .indx(0xc1, 6)
+                .indy(0xd1, 5),
             InstructionBuilder::new(InstructionName::cpx)
-                .imm(0xe0)
-                .zp(0xe4)
-                .abs(0xec),
+                .imm(0xe0, 2)
+                .zp(0xe4, 3)
+                .abs(0xec, 4),
             InstructionBuilder::new(InstructionName::cpy)
-                .imm(0xc0)
-                .zp(0xc4)
-                .abs(0xcc),
+                .imm(0xc0, 2)
+                .zp(0xc4, 3)
+                .abs(0xcc, 4),
 
             // Increments & decrements
             InstructionBuilder::new(InstructionName::inc)
-                .zp(0xe6)
-                .zpx(0xf6)
-                .abs(0xee)
-                .absx(0xfe),
+                .zp(0xe6, 5)
+                .zpx(0xf6, 6)
+                .abs(0xee, 6)
+                .absx(0xfe, 7),
             InstructionBuilder::new(InstructionName::inx)
-                .imp(0xe8),
+                .imp(0xe8, 2),
             InstructionBuilder::new(InstructionName::iny)
-                .imp(0xc8),
+                .imp(0xc8, 2),
             InstructionBuilder::new(InstructionName::dec)
-                .zp(0xc6)
-                .zpx(0xd6)
-                .abs(0xce)
-                .absx(0xde),
+                .zp(0xc6, 5)
+                .zpx(0xd6, 6)
+                .abs(0xce, 6)
+                .absx(0xde, 7),
             InstructionBuilder::new(InstructionName::dex)
-                .imp(0xca),
+                .imp(0xca, 2),
             InstructionBuilder::new(InstructionName::dey)
-                .imp(0x88),
+                .imp(0x88, 2),
 
             // Shifts
             InstructionBuilder::new(InstructionName::asl)
-                .acc(0x0a)
-                .zp(0x06)
-                .zpx(0x16)
-                .abs(0x0e)
-                .absx(0x1e),
+                .acc(0x0a, 2)
+                .zp(0x06, 5)
+                .zpx(0x16, 6)
+                .abs(0x0e, 6)
+                .absx(0x1e, 7),
             InstructionBuilder::new(InstructionName::lsr)
-                .acc(0x4a)
-                .zp(0x46)
-                .zpx(0x56)
-                .abs(0x4e)
-                .absx(0x5e),
+                .acc(0x4a, 2)
+                .zp(0x46, 5)
+                .zpx(0x56, 6)
+                .abs(0x4e, 6)
+                .absx(0x5e, 7),
             InstructionBuilder::new(InstructionName::rol)
-                .acc(0x2a)
-                .zp(0x26)
-                .zpx(0x36)
-                .abs(0x2e)
-                .absx(0x3e),
+                .acc(0x2a, 2)
+                .zp(0x26, 5)
+                .zpx(0x36, 6)
+                .abs(0x2e, 6)
+                .absx(0x3e, 7),
             InstructionBuilder::new(InstructionName::ror)
-                .acc(0x6a)
-                .zp(0x66)
-                .zpx(0x76)
-                .abs(0x6e)
-                .absx(0x7e),
+                .acc(0x6a, 2)
+                .zp(0x66, 5)
+                .zpx(0x76, 6)
+                .abs(0x6e, 6)
+                .absx(0x7e, 7),
 
             // Jumps & calls
             InstructionBuilder::new(InstructionName::jmp)
-                .abs(0x4c)
-                .ind(0x6c),
+                .abs(0x4c, 3)
+                .ind(0x6c, 5),
             InstructionBuilder::new(InstructionName::jsr)
-                .abs(0x20),
+                .abs(0x20, 6),
             InstructionBuilder::new(InstructionName::rts)
-                .imp(0x60),
+                .imp(0x60, 6),
 
             // Branches
             InstructionBuilder::new(InstructionName::bcc)
-                .rel(0x90),
+                .rel(0x90, 2),
             InstructionBuilder::new(InstructionName::bcs)
-                .rel(0xb0),
+                .rel(0xb0, 2),
             InstructionBuilder::new(InstructionName::beq)
-                .rel(0xf0),
+                .rel(0xf0, 2),
             InstructionBuilder::new(InstructionName::bmi)
-                .rel(0x30),
+                .rel(0x30, 2),
             InstructionBuilder::new(InstructionName::bne)
-                .rel(0xd0),
+                .rel(0xd0, 2),
             InstructionBuilder::new(InstructionName::bpl)
-                .rel(0x10),
+                .rel(0x10, 2),
             InstructionBuilder::new(InstructionName::bvc)
-                .rel(0x50),
+                .rel(0x50, 2),
             InstructionBuilder::new(InstructionName::bvs)
-                .rel(0x70),
+                .rel(0x70, 2),
 
             // Status flag changes
             InstructionBuilder::new(InstructionName::clc)
-                .imp(0x18),
+                .imp(0x18, 2),
             InstructionBuilder::new(InstructionName::cld)
-                .imp(0xd8),
+                .imp(0xd8, 2),
             InstructionBuilder::new(InstructionName::cli)
-                .imp(0x58),
+                .imp(0x58, 2),
             InstructionBuilder::new(InstructionName::clv)
-                .imp(0xb8),
+                .imp(0xb8, 2),
             InstructionBuilder::new(InstructionName::sec)
-                .imp(0x38),
+                .imp(0x38, 2),
             InstructionBuilder::new(InstructionName::sed)
-                .imp(0xf8),
+                .imp(0xf8, 2),
             InstructionBuilder::new(InstructionName::sei)
-                .imp(0x78),
+                .imp(0x78, 2),
 
             // System functions
             InstructionBuilder::new(InstructionName::brk)
-                .imp(0x00),
+                .imp(0x00, 7),
             InstructionBuilder::new(InstructionName::nop)
-                .imp(0xea),
+                .imp(0xea, 2),
             InstructionBuilder::new(InstructionName::rti)
-                .imp(0x40),
+                .imp(0x40, 6),
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_op_code_decodes_to_the_mode_that_registered_it() {
+        let registry = InstructionRegistry::new();
+        let mut registered = 0;
+        for builder in &registry.all_instructions {
+            for (&addressing_mode, &op_code) in builder.get_modes() {
+                let instruction = registry.get_instruction_by_op_code(op_code, 0).unwrap();
+                assert_eq!(instruction.name, builder.name, "{op_code:#04x}");
+                assert_eq!(instruction.addressing_mode, addressing_mode, "{op_code:#04x}");
+                assert_eq!(Some(instruction.cycles), builder.get_cycles(addressing_mode));
+                registered += 1;
+            }
+        }
+        // The documented NMOS op codes
+        assert_eq!(registered, 151);
+        let known = (0..=255).filter(|&op_code| registry.get_opcode_info(op_code).is_some());
+        assert_eq!(known.count(), registered);
+    }
+
+    #[test]
+    #[should_panic(expected = "Op code 0xa9 is claimed by both")]
+    fn op_codes_claimed_twice_panic() {
+        let builders = [
+            InstructionBuilder::new(InstructionName::lda).imm(0xa9, 2),
+            InstructionBuilder::new(InstructionName::ldx).imm(0xa9, 2),
+        ];
+        InstructionRegistry::get_op_codes(&builders);
+    }
+}
@@ -1,8 +1,21 @@
 #![feature(let_chains)]
 
+pub mod assembler;
+pub mod breakpoint;
+pub mod callstack;
+pub mod cfg;
+pub mod coverage;
 pub mod decoder;
+pub mod diff;
+pub mod disassembler;
 pub mod emulator;
+pub mod expr;
+pub mod history;
 pub mod instruction;
 pub mod mem;
+pub mod observer;
+pub mod profiler;
 pub mod readwritable;
 pub mod regs;
+pub mod snapshot;
+pub mod trace;
@@ -1,6 +1,7 @@
 use std::fmt::{Display, Formatter};
 
 use crate::readwritable::ReadWritable;
+use crate::snapshot::{SaveState, SnapshotError};
 
 pub const MEM_SIZE: usize = 0x10000;
 pub struct Memory {
@@ -31,6 +32,30 @@ impl ReadWritable for Memory {
         }
         self.buffer[address as usize] = byte;
     }
+
+    fn as_save_state(&self) -> Option<&dyn SaveState> {
+        Some(self)
+    }
+
+    fn as_save_state_mut(&mut self) -> Option<&mut dyn SaveState> {
+        Some(self)
+    }
+}
+
+impl SaveState for Memory {
+    fn save_state(&self) -> Vec<u8> {
+        self.buffer.to_vec()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        self.buffer = state.try_into().map_err(|_| {
+            SnapshotError::InvalidData(format!(
+                "memory state is {} bytes instead of {MEM_SIZE}",
+                state.len()
+            ))
+        })?;
+        Ok(())
+    }
 }
 
 impl Display for Memory {
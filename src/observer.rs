@@ -0,0 +1,145 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::emulator::{InterruptKind, StepResult};
+use crate::instruction::Instruction;
+use crate::regs::Regs;
+
+pub type ObserverId = usize;
+
+/// Callbacks for instrumenting the emulator from the outside. Every method does nothing by
+/// default, so implementors only override the events they care about.
+///
+/// To read the collected data back while the observer is attached, share it through an
+/// `Rc<RefCell<_>>`, which implements this trait for any observer inside it.
+pub trait EmulatorObserver {
+    /// Called before an instruction executes. `regs.pc` is the address of the instruction.
+    fn on_instruction(&mut self, _instruction: &Instruction, _regs: &Regs) {}
+
+    /// Called after every instruction, with the registers it left behind
+    fn on_step(&mut self, _result: &StepResult, _regs: &Regs) {}
+
+    /// Called for every data read, including stack pulls, but not for op code and operand
+    /// fetches
+    fn on_read(&mut self, _address: u16, _value: u8) {}
+
+    /// Called for every write, including stack pushes
+    fn on_write(&mut self, _address: u16, _old: u8, _new: u8) {}
+
+    /// Called once the interrupt sequence has jumped to the vector, including for `brk`
+    fn on_interrupt(&mut self, _kind: InterruptKind) {}
+
+    /// `address` is in the stack page, where `value` was written
+    fn on_stack_push(&mut self, _address: u16, _value: u8) {}
+
+    /// `address` is in the stack page, where `value` was read from
+    fn on_stack_pull(&mut self, _address: u16, _value: u8) {}
+}
+
+impl<T: EmulatorObserver + ?Sized> EmulatorObserver for Rc<RefCell<T>> {
+    fn on_instruction(&mut self, instruction: &Instruction, regs: &Regs) {
+        self.borrow_mut().on_instruction(instruction, regs)
+    }
+
+    fn on_step(&mut self, result: &StepResult, regs: &Regs) {
+        self.borrow_mut().on_step(result, regs)
+    }
+
+    fn on_read(&mut self, address: u16, value: u8) {
+        self.borrow_mut().on_read(address, value)
+    }
+
+    fn on_write(&mut self, address: u16, old: u8, new: u8) {
+        self.borrow_mut().on_write(address, old, new)
+    }
+
+    fn on_interrupt(&mut self, kind: InterruptKind) {
+        self.borrow_mut().on_interrupt(kind)
+    }
+
+    fn on_stack_push(&mut self, address: u16, value: u8) {
+        self.borrow_mut().on_stack_push(address, value)
+    }
+
+    fn on_stack_pull(&mut self, address: u16, value: u8) {
+        self.borrow_mut().on_stack_pull(address, value)
+    }
+}
+
+/// The observers attached to an emulator. Events are passed on to each of them in the order
+/// they were added.
+#[derive(Default)]
+pub struct Observers {
+    observers: Vec<Option<Box<dyn EmulatorObserver>>>,
+}
+
+impl Observers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, observer: Box<dyn EmulatorObserver>) -> ObserverId {
+        self.observers.push(Some(observer));
+        self.observers.len() - 1
+    }
+
+    pub fn remove(&mut self, id: ObserverId) -> Option<Box<dyn EmulatorObserver>> {
+        self.observers.get_mut(id)?.take()
+    }
+
+    pub fn clear(&mut self) {
+        self.observers.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.iter().all(Option::is_none)
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Box<dyn EmulatorObserver>> {
+        self.observers.iter_mut().flatten()
+    }
+}
+
+impl EmulatorObserver for Observers {
+    fn on_instruction(&mut self, instruction: &Instruction, regs: &Regs) {
+        for observer in self.iter_mut() {
+            observer.on_instruction(instruction, regs);
+        }
+    }
+
+    fn on_step(&mut self, result: &StepResult, regs: &Regs) {
+        for observer in self.iter_mut() {
+            observer.on_step(result, regs);
+        }
+    }
+
+    fn on_read(&mut self, address: u16, value: u8) {
+        for observer in self.iter_mut() {
+            observer.on_read(address, value);
+        }
+    }
+
+    fn on_write(&mut self, address: u16, old: u8, new: u8) {
+        for observer in self.iter_mut() {
+            observer.on_write(address, old, new);
+        }
+    }
+
+    fn on_interrupt(&mut self, kind: InterruptKind) {
+        for observer in self.iter_mut() {
+            observer.on_interrupt(kind);
+        }
+    }
+
+    fn on_stack_push(&mut self, address: u16, value: u8) {
+        for observer in self.iter_mut() {
+            observer.on_stack_push(address, value);
+        }
+    }
+
+    fn on_stack_pull(&mut self, address: u16, value: u8) {
+        for observer in self.iter_mut() {
+            observer.on_stack_pull(address, value);
+        }
+    }
+}
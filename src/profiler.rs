@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::emulator::{InterruptKind, StepResult};
+use crate::instruction::InstructionName;
+use crate::observer::EmulatorObserver;
+use crate::regs::Regs;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct AddressStats {
+    pub executions: u64,
+    /// Includes the cycles spent servicing an interrupt before the instruction
+    pub cycles: u64,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SubroutineStats {
+    pub calls: u64,
+    /// The cycles spent in the subroutine and everything it called. Recursive calls are only
+    /// counted once.
+    pub inclusive_cycles: u64,
+    /// The cycles spent in the subroutine itself
+    pub exclusive_cycles: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Frame {
+    entry: u16,
+    start_cycles: u64,
+    // Whether the frame is on the call stack path, which interrupts into the root are not
+    on_path: bool,
+}
+
+/// Counts executions and cycles per address, and attributes cycles to subroutines by pairing
+/// `jsr` with `rts` and interrupts with `rti`. Attach it with `Emulator::add_observer`, through
+/// an `Rc<RefCell<Profiler>>` to read the results back.
+///
+/// The first instruction profiled is the entry of the root frame, which is never left. An `rts`
+/// or `rti` that would leave it, e.g. one used as an indirect jump, is counted but does not
+/// change the call stack. An interrupt or `brk` whose handler is the root's entry, e.g. a `brk`
+/// vectored back to the start of the program, stays in the root instead of calling it again.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    addresses: HashMap<u16, AddressStats>,
+    subroutines: HashMap<u16, SubroutineStats>,
+    // The cycles spent with each call stack, outermost frame first
+    stacks: HashMap<Vec<u16>, u64>,
+    frames: Vec<Frame>,
+    path: Vec<u16>,
+    labels: HashMap<u16, String>,
+    total_cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names subroutines and addresses in the reports, e.g. with `Program::labels`. When several
+    /// labels share an address, the first in alphabetical order is used.
+    pub fn set_labels(&mut self, labels: &HashMap<String, u16>) {
+        let mut names = labels.iter().collect::<Vec<_>>();
+        names.sort();
+        self.labels.clear();
+        for (name, &address) in names {
+            self.labels.entry(address).or_insert_with(|| name.clone());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.addresses.clear();
+        self.subroutines.clear();
+        self.stacks.clear();
+        self.frames.clear();
+        self.path.clear();
+        self.total_cycles = 0;
+    }
+
+    pub fn get_total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    pub fn get_addresses(&self) -> &HashMap<u16, AddressStats> {
+        &self.addresses
+    }
+
+    /// The statistics of every subroutine entered so far. Subroutines that have not returned
+    /// yet include the cycles spent in them up to now.
+    pub fn get_subroutines(&self) -> HashMap<u16, SubroutineStats> {
+        let mut subroutines = self.subroutines.clone();
+        for (depth, frame) in self.frames.iter().enumerate() {
+            let is_outermost = !self.frames[..depth]
+                .iter()
+                .any(|outer| outer.entry == frame.entry);
+            if is_outermost {
+                subroutines.entry(frame.entry).or_default().inclusive_cycles +=
+                    self.total_cycles - frame.start_cycles;
+            }
+        }
+        subroutines
+    }
+
+    /// The label of `address`, or the address in hexadecimal
+    pub fn get_name(&self, address: u16) -> String {
+        match self.labels.get(&address) {
+            Some(label) => label.clone(),
+            None => format!("${address:04x}"),
+        }
+    }
+
+    /// Writes the subroutines sorted by exclusive cycles, then the addresses sorted by cycles
+    pub fn write_report<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut subroutines = self.get_subroutines().into_iter().collect::<Vec<_>>();
+        subroutines
+            .sort_by_key(|&(address, stats)| (std::cmp::Reverse(stats.exclusive_cycles), address));
+        writeln!(writer, "Total cycles: {}", self.total_cycles)?;
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "{:>10} {:>12} {:>12}  subroutine",
+            "calls", "inclusive", "exclusive"
+        )?;
+        for (address, stats) in subroutines {
+            writeln!(
+                writer,
+                "{:>10} {:>12} {:>12}  {}",
+                stats.calls,
+                stats.inclusive_cycles,
+                stats.exclusive_cycles,
+                self.get_name(address)
+            )?;
+        }
+
+        let mut addresses = self.addresses.iter().collect::<Vec<_>>();
+        addresses.sort_by_key(|&(&address, stats)| (std::cmp::Reverse(stats.cycles), address));
+        writeln!(writer)?;
+        writeln!(writer, "{:>10} {:>12}  address", "executions", "cycles")?;
+        for (&address, stats) in addresses {
+            let label = match self.labels.get(&address) {
+                Some(label) => format!(" ({label})"),
+                None => String::new(),
+            };
+            writeln!(
+                writer,
+                "{:>10} {:>12}  ${address:04x}{label}",
+                stats.executions, stats.cycles
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes one line per call stack with the cycles spent in it, in the collapsed format that
+    /// flamegraph tools such as inferno and flamegraph.pl read
+    pub fn write_collapsed_stacks<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut lines = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let names = stack
+                    .iter()
+                    .map(|&address| self.get_name(address))
+                    .collect::<Vec<_>>();
+                (names.join(";"), cycles)
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        for (stack, cycles) in lines {
+            writeln!(writer, "{stack} {cycles}")?;
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self, entry: u16) {
+        self.frames.push(Frame {
+            entry,
+            start_cycles: self.total_cycles,
+            on_path: true,
+        });
+        self.path.push(entry);
+        self.subroutines.entry(entry).or_default().calls += 1;
+    }
+
+    fn enter_interrupt(&mut self, handler: u16) {
+        if self
+            .frames
+            .first()
+            .is_some_and(|root| root.entry == handler)
+        {
+            // Its `rti` still has to be paired with a frame
+            self.frames.push(Frame {
+                entry: handler,
+                start_cycles: self.total_cycles,
+                on_path: false,
+            });
+        } else {
+            self.enter(handler);
+        }
+    }
+
+    fn leave(&mut self) {
+        if self.frames.len() <= 1 {
+            return;
+        }
+        let frame = self.frames.pop().unwrap();
+        if frame.on_path {
+            self.path.pop();
+        }
+        if !self.frames.iter().any(|outer| outer.entry == frame.entry) {
+            self.subroutines
+                .entry(frame.entry)
+                .or_default()
+                .inclusive_cycles += self.total_cycles - frame.start_cycles;
+        }
+    }
+}
+
+impl EmulatorObserver for Profiler {
+    fn on_step(&mut self, result: &StepResult, regs: &Regs) {
+        if self.frames.is_empty() {
+            self.enter(result.address);
+        }
+        // An interrupt taken before the instruction belongs to its handler, a brk to the caller
+        if matches!(
+            result.interrupt,
+            Some(InterruptKind::Nmi | InterruptKind::Irq)
+        ) {
+            self.enter_interrupt(result.address);
+        }
+
+        let cycles = result.cycles as u64;
+        self.total_cycles += cycles;
+        let stats = self.addresses.entry(result.address).or_default();
+        stats.executions += 1;
+        stats.cycles += cycles;
+        let entry = self.frames.last().unwrap().entry;
+        self.subroutines.entry(entry).or_default().exclusive_cycles += cycles;
+        match self.stacks.get_mut(self.path.as_slice()) {
+            Some(stack_cycles) => *stack_cycles += cycles,
+            None => {
+                self.stacks.insert(self.path.clone(), cycles);
+            }
+        }
+
+        match result.instruction.map(|ins| ins.name) {
+            Some(InstructionName::jsr) => self.enter(regs.pc),
+            Some(InstructionName::brk) => self.enter_interrupt(regs.pc),
+            Some(InstructionName::rts | InstructionName::rti) => self.leave(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::emulator::{Emulator, IRQ_VEC_LOW_ADDR};
+    use crate::mem::Memory;
+    use crate::readwritable::ReadWritable;
+
+    // Runs `source` from $0200 for `count` instructions with `brk` vectored to `brk_handler`
+    fn profile(source: &str, brk_handler: u16, count: usize) -> Profiler {
+        let image = assemble(&format!(".org $0200\n{source}")).unwrap();
+        let mut emulator = Emulator::new(Memory::new_from_bytes(image));
+        emulator
+            .get_bus_mut()
+            .write(IRQ_VEC_LOW_ADDR, brk_handler as u8);
+        emulator
+            .get_bus_mut()
+            .write(IRQ_VEC_LOW_ADDR + 1, (brk_handler >> 8) as u8);
+        emulator.get_regs_mut().pc = 0x0200;
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        emulator.add_observer(Box::new(profiler.clone()));
+        for _ in 0..count {
+            emulator.step();
+        }
+        let profiler = profiler.borrow().clone();
+        profiler
+    }
+
+    #[test]
+    fn cycles_are_attributed_inclusively_and_exclusively() {
+        // jsr 6, nop 2, rts 6, then the brk that ends it 7
+        let profiler = profile("start:\njsr sub\nbrk\nsub:\nnop\nrts", 0x0200, 4);
+        let subroutines = profiler.get_subroutines();
+        let start = subroutines[&0x0200];
+        let sub = subroutines[&0x0204];
+        assert_eq!((start.calls, start.exclusive_cycles), (1, 13));
+        assert_eq!(start.inclusive_cycles, 21);
+        assert_eq!(
+            (sub.calls, sub.exclusive_cycles, sub.inclusive_cycles),
+            (1, 8, 8)
+        );
+        assert_eq!(profiler.get_total_cycles(), 21);
+        assert_eq!(profiler.get_addresses()[&0x0200].cycles, 6);
+    }
+
+    #[test]
+    fn a_brk_vectored_to_the_root_stays_in_it() {
+        let profiler = profile("start:\nnop\nbrk\nnop", 0x0200, 5);
+        let start = profiler.get_subroutines()[&0x0200];
+        assert_eq!(start.calls, 1);
+        assert_eq!(start.exclusive_cycles, 2 + 7 + 2 + 7 + 2);
+
+        let mut stacks = Vec::new();
+        profiler.write_collapsed_stacks(&mut stacks).unwrap();
+        assert_eq!(String::from_utf8(stacks).unwrap(), "$0200 20\n");
+    }
+
+    #[test]
+    fn interrupt_handlers_are_frames_of_their_own() {
+        let profiler = profile("start:\nbrk\nnop\nnop\nhandler:\nrti", 0x0203, 3);
+        let subroutines = profiler.get_subroutines();
+        assert_eq!(subroutines[&0x0203].calls, 1);
+        assert_eq!(subroutines[&0x0203].exclusive_cycles, 6);
+        assert_eq!(subroutines[&0x0200].exclusive_cycles, 7 + 2);
+
+        let mut stacks = Vec::new();
+        profiler.write_collapsed_stacks(&mut stacks).unwrap();
+        let stacks = String::from_utf8(stacks).unwrap();
+        assert_eq!(stacks, "$0200 9\n$0200;$0203 6\n");
+    }
+}
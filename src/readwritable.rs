@@ -1,4 +1,44 @@
+use crate::snapshot::SaveState;
+
 pub trait ReadWritable {
     fn read(&self, address: u16) -> u8;
     fn write(&mut self, address: u16, byte: u8);
+
+    /// Reads a byte without the side effects a read can have on memory-mapped I/O, e.g.
+    /// acknowledging an interrupt. The debugging tools use it to look at memory the program
+    /// does not read itself. Buses with such registers should override it.
+    fn peek(&self, address: u16) -> u8 {
+        self.read(address)
+    }
+
+    /// Devices that implement `SaveState` return themselves so that snapshots include them
+    fn as_save_state(&self) -> Option<&dyn SaveState> {
+        None
+    }
+
+    fn as_save_state_mut(&mut self) -> Option<&mut dyn SaveState> {
+        None
+    }
+}
+
+impl<T: ReadWritable + ?Sized> ReadWritable for Box<T> {
+    fn read(&self, address: u16) -> u8 {
+        (**self).read(address)
+    }
+
+    fn write(&mut self, address: u16, byte: u8) {
+        (**self).write(address, byte)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        (**self).peek(address)
+    }
+
+    fn as_save_state(&self) -> Option<&dyn SaveState> {
+        (**self).as_save_state()
+    }
+
+    fn as_save_state_mut(&mut self) -> Option<&mut dyn SaveState> {
+        (**self).as_save_state_mut()
+    }
 }
@@ -12,6 +12,7 @@ bitflags! {
         const INT_DISABLE   = 0b0000_0100;
         const DEC_MODE      = 0b0000_1000;
         const BREAK         = 0b0001_0000;
+        const UNUSED        = 0b0010_0000;
         const OVERFLOW      = 0b0100_0000;
         const NEG           = 0b1000_0000;
     }
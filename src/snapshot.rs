@@ -0,0 +1,185 @@
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"M65S";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    NotASnapshot,
+    UnsupportedVersion(u16),
+    InvalidData(String),
+    /// The snapshot has device state but the bus does not implement `SaveState`
+    MissingDevice,
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "{err}"),
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(
+                f,
+                "snapshot format version {version} is not supported, expected version {SNAPSHOT_VERSION}"
+            ),
+            SnapshotError::InvalidData(reason) => write!(f, "invalid snapshot: {reason}"),
+            SnapshotError::MissingDevice => {
+                write!(f, "the snapshot has device state but the bus cannot restore it")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                SnapshotError::InvalidData("unexpected end of snapshot".to_string())
+            }
+            _ => SnapshotError::Io(err),
+        }
+    }
+}
+
+/// Lets a device behind `ReadWritable` contribute its state to emulator snapshots. Hook it up
+/// by overriding `ReadWritable::as_save_state` and `ReadWritable::as_save_state_mut`.
+pub trait SaveState {
+    fn save_state(&self) -> Vec<u8>;
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError>;
+}
+
+pub(crate) fn write_u8<W: Write>(writer: &mut W, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+pub(crate) fn write_u16<W: Write>(writer: &mut W, value: u16) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+pub(crate) fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_bool<R: Read>(reader: &mut R) -> Result<bool, SnapshotError> {
+    match read_u8(reader)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        value => Err(SnapshotError::InvalidData(format!(
+            "{value} is not a boolean"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::emulator::{CpuVariant, Emulator};
+    use crate::mem::Memory;
+    use crate::readwritable::ReadWritable;
+
+    fn save(emulator: &Emulator<Memory>) -> Vec<u8> {
+        let mut snapshot = Vec::new();
+        emulator.save_snapshot(&mut snapshot).unwrap();
+        snapshot
+    }
+
+    fn new_emulator() -> Emulator<Memory> {
+        let image = assemble(".org $0200\nlda #$42\nsta $10\ninx\nbrk\n").unwrap();
+        let mut emulator = Emulator::new(Memory::new_from_bytes(image));
+        emulator.get_regs_mut().pc = 0x0200;
+        emulator
+    }
+
+    #[test]
+    fn restoring_a_snapshot_round_trips_the_emulator_and_the_memory() {
+        let mut emulator = new_emulator();
+        emulator.set_variant(CpuVariant::Cmos65C02);
+        emulator.step();
+        emulator.trigger_nmi();
+        let snapshot = save(&emulator);
+
+        let mut restored = Emulator::new(Memory::new());
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.get_regs(), emulator.get_regs());
+        assert_eq!(restored.get_cycles(), emulator.get_cycles());
+        assert_eq!(restored.get_variant(), CpuVariant::Cmos65C02);
+        assert!(restored.is_nmi_pending());
+        assert_eq!(restored.get_bus().read(0x0202), 0x85);
+        assert_eq!(save(&restored), snapshot);
+    }
+
+    #[test]
+    fn bad_magic_is_not_a_snapshot() {
+        let mut snapshot = save(&new_emulator());
+        snapshot[0] = b'X';
+        let result = new_emulator().load_snapshot(snapshot.as_slice());
+        assert!(matches!(result, Err(SnapshotError::NotASnapshot)));
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let mut snapshot = save(&new_emulator());
+        snapshot[4..6].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        let result = new_emulator().load_snapshot(snapshot.as_slice());
+        assert!(matches!(
+            result,
+            Err(SnapshotError::UnsupportedVersion(version)) if version == SNAPSHOT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn truncated_snapshots_leave_the_emulator_untouched() {
+        let mut source = new_emulator();
+        source.step();
+        let snapshot = save(&source);
+        for len in [12, snapshot.len() - 1] {
+            let mut emulator = new_emulator();
+            let result = emulator.load_snapshot(&snapshot[..len]);
+            assert!(
+                matches!(result, Err(SnapshotError::InvalidData(_))),
+                "{len}"
+            );
+            assert_eq!(emulator.get_regs().pc, 0x0200);
+            assert_eq!(emulator.get_cycles(), 0);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::instruction::Instruction;
+use crate::regs::{CpuFlags, Regs};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    /// The column layout of nestest.log, without the PPU column:
+    /// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7`
+    #[default]
+    Nestest,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nestest" => Ok(TraceFormat::Nestest),
+            "json" => Ok(TraceFormat::Json),
+            _ => Err(format!("Unknown trace format: {s}")),
+        }
+    }
+}
+
+impl Display for TraceFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceFormat::Nestest => write!(f, "nestest"),
+            TraceFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// The state of the CPU just before an instruction executes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceLine {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// `None` for illegal op codes
+    pub instruction: Option<Instruction>,
+    pub regs: Regs,
+    pub cycles: u64,
+}
+
+impl TraceLine {
+    pub fn disassemble(&self) -> String {
+        let Some(ins) = self.instruction else {
+            return format!(".BYTE ${:02X}", self.bytes.first().copied().unwrap_or(0));
+        };
+        // Branches show their target like nestest.log does
+        ins.disassemble(self.address).to_uppercase()
+    }
+
+    /// The status register as the CPU would push it with `php`, minus the break flag
+    pub fn get_status(&self) -> u8 {
+        (self.regs.flags | CpuFlags::UNUSED).bits()
+    }
+
+    pub fn to_nestest(&self) -> String {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            "{:04X}  {:<8}  {:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.address,
+            bytes,
+            self.disassemble(),
+            self.regs.a,
+            self.regs.x,
+            self.regs.y,
+            self.get_status(),
+            self.regs.sp,
+            self.cycles
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|byte| byte.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"pc\":{},\"bytes\":[{}],\"asm\":\"{}\",\"a\":{},\"x\":{},\"y\":{},\"p\":{},\"sp\":{},\"cyc\":{}}}",
+            self.address,
+            bytes,
+            self.disassemble(),
+            self.regs.a,
+            self.regs.x,
+            self.regs.y,
+            self.get_status(),
+            self.regs.sp,
+            self.cycles
+        )
+    }
+
+    pub fn format(&self, format: TraceFormat) -> String {
+        match format {
+            TraceFormat::Nestest => self.to_nestest(),
+            TraceFormat::Json => self.to_json(),
+        }
+    }
+}
+
+/// Writes a line per executed instruction. Tracing stops at the first write error, which
+/// `take_error` returns.
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    format: TraceFormat,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>, format: TraceFormat) -> Self {
+        Self {
+            writer,
+            format,
+            error: None,
+        }
+    }
+
+    pub fn get_format(&self) -> TraceFormat {
+        self.format
+    }
+
+    pub fn trace(&mut self, line: &TraceLine) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(err) = writeln!(self.writer, "{}", line.format(self.format)) {
+            self.error = Some(err);
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}